@@ -1,4 +1,5 @@
 use ipnet::IpNet;
+use serde::Serialize;
 use std::net::IpAddr;
 use std::net::ToSocketAddrs;
 use std::str::FromStr;
@@ -15,32 +16,120 @@ pub fn local(address_family: &str, address: &Option<String>) -> Vec<String> {
         }
         vec![]
     } else {
-        match address_family {
-            "ip4" => vec!["0.0.0.0/0".to_string()],
-            "ip6" => vec!["::/0".to_string()],
-            _ => vec![],
-        }
+        any(address_family)
     }
 }
 
-pub fn remote(address_family: &str, address: &Option<String>) -> Vec<String> {
-    let mut addresses = match address_family {
+/// Address of a local endpoint given as an ip or cidr.
+pub fn host(address: &str) -> Option<IpAddr> {
+    IpAddr::from_str(address)
+        .ok()
+        .or_else(|| IpNet::from_str(address).ok().map(|cidr| cidr.addr()))
+}
+
+pub fn any(address_family: &str) -> Vec<String> {
+    match address_family {
         "ip4" => vec!["0.0.0.0/0".to_string()],
         "ip6" => vec!["::/0".to_string()],
         _ => vec![],
-    };
+    }
+}
+
+pub fn resolve(address_family: &str, address: &str) -> Option<String> {
+    (address, 0)
+        .to_socket_addrs()
+        .unwrap_or_else(|_| vec![].into_iter())
+        .find(|addr| match address_family {
+            "ip4" => addr.is_ipv4(),
+            "ip6" => addr.is_ipv6(),
+            _ => false,
+        })
+        .map(|addr| addr.ip().to_string())
+}
+
+/// How far an address is routed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Scope {
+    /// loopback, link-local, unspecified or multicast, never dialable by a peer
+    Link,
+    /// private, cgnat or unique local, only dialable from within a private network
+    Site,
+    Global,
+}
+
+fn scope(address: IpAddr) -> Scope {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, ..] = address.octets();
+            if address.is_loopback()
+                || address.is_link_local()
+                || address.is_unspecified()
+                || address.is_broadcast()
+                || address.is_multicast()
+            {
+                Scope::Link
+            } else if address.is_private() || (a == 100 && (b & 0xc0) == 64) {
+                Scope::Site
+            } else {
+                Scope::Global
+            }
+        }
+        IpAddr::V6(address) => {
+            let first = address.segments()[0];
+            if address.is_loopback()
+                || address.is_unspecified()
+                || address.is_multicast()
+                || (first & 0xffc0) == 0xfe80
+            {
+                Scope::Link
+            } else if (first & 0xfe00) == 0xfc00 {
+                Scope::Site
+            } else {
+                Scope::Global
+            }
+        }
+    }
+}
+
+/// Whether an end at `from`, if known, can dial the resolved address `to`.
+pub fn dialable(from: Option<IpAddr>, to: Option<IpAddr>) -> bool {
+    match to.map(scope) {
+        Some(Scope::Global) => true,
+        Some(Scope::Site) => from.is_none_or(|from| scope(from) != Scope::Global),
+        Some(Scope::Link) | None => false,
+    }
+}
+
+/// Which end of a pair of endpoints can dial the other.
+#[derive(Debug, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Reachability {
+    /// the remote end has an address we can dial
+    Reachable,
+    /// only the remote end can dial us
+    ResponderOnly,
+    /// neither end has an address the other can dial, such as both being behind nat
+    Unreachable,
+}
+
+impl Reachability {
+    /// Classifies a pair by the resolved addresses each end publishes.
+    pub fn classify(local: Option<IpAddr>, remote: Option<IpAddr>) -> Self {
+        if dialable(local, remote) {
+            Reachability::Reachable
+        } else if dialable(remote, local) {
+            Reachability::ResponderOnly
+        } else {
+            Reachability::Unreachable
+        }
+    }
+}
+
+pub fn remote(address_family: &str, address: &Option<String>) -> Vec<String> {
+    let mut addresses = any(address_family);
 
     if let Some(address) = address {
-        if let Some(address) = (address.as_str(), 0)
-            .to_socket_addrs()
-            .unwrap_or_else(|_| vec![].into_iter())
-            .find(|addr| match address_family {
-                "ip4" => addr.is_ipv4(),
-                "ip6" => addr.is_ipv6(),
-                _ => false,
-            })
-            .map(|addr| addr.ip().to_string())
-        {
+        if let Some(address) = resolve(address_family, address) {
             addresses.push(address);
         }
     }
@@ -71,4 +160,32 @@ mod test {
         insta::assert_yaml_snapshot!(super::remote("ip4", &Some("localhost".to_string())),);
         insta::assert_yaml_snapshot!(super::remote("ip6", &Some("localhost".to_string())),);
     }
+
+    #[test]
+    fn classify() {
+        let cases = [
+            ("public", Some("192.0.2.1"), Some("198.51.100.1")),
+            ("public ip6", Some("2001:db8::1"), Some("2001:db8::2")),
+            ("private to public", Some("10.0.0.1"), Some("192.0.2.1")),
+            ("public to private", Some("192.0.2.1"), Some("10.0.0.1")),
+            ("private", Some("10.0.0.1"), Some("192.168.0.1")),
+            ("cgnat to public", Some("100.64.0.1"), Some("192.0.2.1")),
+            ("public to cgnat", Some("192.0.2.1"), Some("100.127.0.1")),
+            ("link-local", Some("169.254.0.1"), Some("169.254.0.2")),
+            ("link-local ip6", Some("fe80::1"), Some("2001:db8::1")),
+            ("ula", Some("fd00::1"), Some("fd00::2")),
+            ("ula to public", Some("fd00::1"), Some("2001:db8::1")),
+            ("unresolved to public", None, Some("192.0.2.1")),
+            ("unresolved to private", None, Some("10.0.0.1")),
+            ("public to unresolved", Some("192.0.2.1"), None),
+            ("unresolved", None, None),
+        ];
+        insta::assert_yaml_snapshot!(cases.map(|(name, local, remote)| {
+            let parse = |address: Option<&str>| address.map(|address| address.parse().unwrap());
+            (
+                name,
+                super::Reachability::classify(parse(local), parse(remote)),
+            )
+        }));
+    }
 }
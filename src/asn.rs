@@ -1,17 +1,160 @@
+use crate::error::Error;
 use const_oid::db::rfc4519::{CN, ORGANIZATION_NAME, SERIAL_NUMBER};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use x509_cert::{
     attr::AttributeTypeAndValue,
-    der::asn1::{PrintableString, SetOfVec, Utf8StringRef},
-    der::{Encode, Result},
+    der::asn1::{PrintableString, PrintableStringRef, SetOfVec, Utf8StringRef},
+    der::{Decode, Encode},
     name::{RdnSequence, RelativeDistinguishedName},
 };
 
+const PREFIX: &str = "asn1dn:#";
+
+// upper bound from X.520, only enforced for the opt-in utf8 serial number encoding,
+// as deployed printable identities predate it and organization and common names
+// do not depend on that encoding
+const UB_SERIAL_NUMBER: usize = 64;
+
+/// String type of the serial number attribute, printable unless opted out.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// PrintableString, restricted to letters, digits, space and '()+,-./:=?
+    #[default]
+    Printable,
+    /// UTF8String, for serial numbers that do not fit a PrintableString
+    Utf8,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Identity {
+    pub organization: String,
+    pub common_name: String,
+    pub serial_number: String,
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}",
+            self.organization, self.common_name, self.serial_number
+        )
+    }
+}
+
 pub fn encode_identity(
     organization: &str,
     common_name: &str,
     serial_number: &str,
-) -> Result<String> {
-    let name = RdnSequence::from(vec![
+    encoding: Encoding,
+) -> Result<String, Error> {
+    let name = identity_name(organization, common_name, serial_number, encoding)?.to_der()?;
+    Ok(format!("{}{}", PREFIX, hex::encode(name)))
+}
+
+pub fn decode_identity(identity: &str) -> Result<Identity, Error> {
+    let invalid = || Error::InvalidIdentity(identity.to_string());
+
+    let name = identity.strip_prefix(PREFIX).ok_or_else(invalid)?;
+    let name = RdnSequence::from_der(&hex::decode(name).map_err(|_| invalid())?)?;
+
+    let mut organization = None;
+    let mut common_name = None;
+    let mut serial_number = None;
+    for attribute in name.iter().flat_map(|rdn| rdn.iter()) {
+        let value = match attribute.value.decode_as::<Utf8StringRef>() {
+            Ok(value) => value.to_string(),
+            Err(_) => attribute
+                .value
+                .decode_as::<PrintableStringRef>()?
+                .to_string(),
+        };
+        match attribute.oid {
+            ORGANIZATION_NAME => organization = Some(value),
+            CN => common_name = Some(value),
+            SERIAL_NUMBER => serial_number = Some(value),
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(Identity {
+        organization: organization.ok_or_else(invalid)?,
+        common_name: common_name.ok_or_else(invalid)?,
+        serial_number: serial_number.ok_or_else(invalid)?,
+    })
+}
+
+/// Replaces every identity in a line, such as from charon logs, with its decoded form.
+pub fn decode_identities(line: &str) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(PREFIX) {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest[PREFIX.len()..]
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .map_or(rest.len(), |end| end + PREFIX.len());
+        match decode_identity(&rest[..end]) {
+            Ok(identity) => result.push_str(&identity.to_string()),
+            Err(_) => result.push_str(&rest[..end]),
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn check_length(attribute: &str, value: &str, bound: usize) -> Result<(), Error> {
+    if value.chars().count() > bound {
+        return Err(Error::InvalidIdentity(format!(
+            "{} \"{}\" exceeds {} characters",
+            attribute, value, bound
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the attributes can be encoded as an identity.
+pub fn validate_identity(
+    organization: &str,
+    common_name: &str,
+    serial_number: &str,
+    encoding: Encoding,
+) -> Result<(), Error> {
+    identity_name(organization, common_name, serial_number, encoding).map(|_| ())
+}
+
+pub fn organization_name(organization: &str) -> Result<RdnSequence, Error> {
+    Ok(RdnSequence::from(vec![RelativeDistinguishedName::from(
+        SetOfVec::from_iter([AttributeTypeAndValue {
+            oid: ORGANIZATION_NAME,
+            value: Utf8StringRef::new(organization)?.into(),
+        }])?,
+    )]))
+}
+
+pub fn identity_name(
+    organization: &str,
+    common_name: &str,
+    serial_number: &str,
+    encoding: Encoding,
+) -> Result<RdnSequence, Error> {
+    let serial_number = match encoding {
+        Encoding::Printable => (&PrintableString::new(serial_number).map_err(|_| {
+            Error::InvalidIdentity(format!(
+                "serial_number \"{}\" is not a printable string, consider the utf8 encoding",
+                serial_number
+            ))
+        })?)
+            .into(),
+        Encoding::Utf8 => {
+            check_length("serial_number", serial_number, UB_SERIAL_NUMBER)?;
+            Utf8StringRef::new(serial_number)?.into()
+        }
+    };
+    Ok(RdnSequence::from(vec![
         RelativeDistinguishedName::from(SetOfVec::from_iter([AttributeTypeAndValue {
             oid: ORGANIZATION_NAME,
             value: Utf8StringRef::new(organization)?.into(),
@@ -22,18 +165,85 @@ pub fn encode_identity(
         }])?),
         RelativeDistinguishedName::from(SetOfVec::from_iter([AttributeTypeAndValue {
             oid: SERIAL_NUMBER,
-            value: (&PrintableString::new(serial_number)?).into(),
+            value: serial_number,
         }])?),
-    ])
-    .to_der()?;
-    Ok(format!("asn1dn:#{}", hex::encode(name)))
+    ]))
 }
 
 #[cfg(test)]
 mod test {
+    use proptest::prelude::*;
+
     #[test]
     fn encode_identity() {
-        let identity = super::encode_identity("acme organization", "some server", "0").unwrap();
+        let identity = super::encode_identity(
+            "acme organization",
+            "some server",
+            "0",
+            super::Encoding::Printable,
+        )
+        .unwrap();
         insta::assert_yaml_snapshot!(identity);
     }
+
+    #[test]
+    fn decode_identities() {
+        let identity = super::encode_identity(
+            "acme organization",
+            "some server",
+            "0",
+            super::Encoding::Printable,
+        )
+        .unwrap();
+        insta::assert_snapshot!(super::decode_identities(&format!(
+            "IKE_SA {}[1] established between 10.0.0.1[{}]...10.0.0.2[asn1dn:#00]",
+            identity, identity
+        )));
+    }
+
+    proptest! {
+        #[test]
+        fn identity_roundtrip(
+            organization in "\\PC{0,64}",
+            common_name in "\\PC{0,64}",
+            serial_number in "[A-Za-z0-9 '()+,./:=?-]{0,64}",
+        ) {
+            let identity = super::encode_identity(
+                &organization,
+                &common_name,
+                &serial_number,
+                super::Encoding::Printable,
+            )
+            .unwrap();
+            let decoded = super::decode_identity(&identity).unwrap();
+            prop_assert_eq!(decoded.organization, organization);
+            prop_assert_eq!(decoded.common_name, common_name);
+            prop_assert_eq!(decoded.serial_number, serial_number);
+        }
+
+        #[test]
+        fn identity_roundtrip_utf8(serial_number in "\\PC{0,64}") {
+            let identity = super::encode_identity(
+                "acme organization",
+                "some server",
+                &serial_number,
+                super::Encoding::Utf8,
+            )
+            .unwrap();
+            prop_assert_eq!(super::decode_identity(&identity).unwrap().serial_number, serial_number);
+        }
+    }
+
+    #[test]
+    fn validate_identity() {
+        let long = "x".repeat(65);
+        insta::assert_yaml_snapshot!([
+            super::validate_identity("acme", &long, "0", super::Encoding::Utf8),
+            super::validate_identity("acme", "server", &long, super::Encoding::Printable),
+            super::validate_identity("acme", "server", &long, super::Encoding::Utf8),
+            super::validate_identity("acme", "server", "node_0", super::Encoding::Printable),
+            super::validate_identity("acme", "server", "node_0", super::Encoding::Utf8),
+        ]
+        .map(|result| result.map_err(|err| err.to_string())));
+    }
 }
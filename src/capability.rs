@@ -0,0 +1,79 @@
+use crate::{
+    config::{Auth, Config},
+    error::Error,
+    key,
+    registry::Registry,
+    vici::{Transport, Version},
+};
+use serde::Serialize;
+
+/// What the running charon offers, queried before relying on any of it.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Capabilities {
+    pub version: Version,
+    pub plugins: Vec<String>,
+}
+
+impl Capabilities {
+    pub async fn query(transport: &mut dyn Transport) -> Result<Self, Error> {
+        Ok(Self {
+            version: transport.version().await?,
+            plugins: transport.plugins().await?,
+        })
+    }
+
+    /// Checks that charon supports every feature the config, registry and credentials make use of.
+    pub fn check(
+        &self,
+        config: &Config,
+        registry: &Registry,
+        key: &key::Source,
+        certificates: &[String],
+    ) -> Result<(), Error> {
+        if config.experimental.iptfs {
+            let version = self.version.semver()?;
+            if version < semver::Version::new(6, 0, 0) {
+                return Err(Error::Unsupported(format!(
+                    "iptfs mode, which requires charon 6.0.0 or later but found {}",
+                    self.version.version
+                )));
+            }
+        }
+
+        let mut plugins = vec![];
+        match config.auth {
+            Auth::Pubkey => plugins.push(("pubkey", "raw public key authentication")),
+            Auth::Certificate => plugins.push(("x509", "certificate authentication")),
+        }
+        if !certificates.is_empty() {
+            plugins.push(("x509", "loading certificates"));
+        }
+        if registry
+            .iter()
+            .any(|organization| organization.ca_certificate.is_some())
+        {
+            plugins.push(("x509", "authenticating peers by certificate"));
+        }
+        if let key::Source::Token(_) = key {
+            plugins.push(("pkcs11", "keys on PKCS#11 tokens"));
+        }
+        if config
+            .endpoints
+            .iter()
+            .any(|endpoint| endpoint.updown.is_some())
+        {
+            plugins.push(("updown", "updown scripts"));
+        }
+
+        for (plugin, feature) in plugins {
+            if !self.plugins.iter().any(|loaded| loaded == plugin) {
+                return Err(Error::Unsupported(format!(
+                    "{}, which requires the {} plugin",
+                    feature, plugin
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
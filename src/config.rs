@@ -1,3 +1,9 @@
+use crate::{
+    asn::{self, Encoding},
+    error::Error,
+    policy::Policy,
+    topology::Topology,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -7,6 +13,16 @@ pub struct Config {
     pub common_name: String,
     pub endpoints: Vec<Endpoint>,
     #[serde(default)]
+    pub auth: Auth,
+    #[serde(default)]
+    pub parameters: Parameters,
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default)]
+    pub topology: Topology,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
     pub experimental: Experimental,
 }
 
@@ -14,12 +30,20 @@ pub struct Config {
 #[serde(deny_unknown_fields)]
 pub struct Endpoint {
     pub serial_number: String,
+    #[serde(default)]
+    pub serial_number_encoding: Encoding,
     pub address_family: String,
     pub address: Option<String>,
     pub port: u16,
 
     pub updown: Option<String>,
     pub fwmark: Option<String>,
+    /// whether this endpoint requires udp encapsulation, used until the node is in the registry
+    #[serde(default)]
+    pub encap: Option<bool>,
+    /// whether this endpoint supports mobike, used until the node is in the registry
+    #[serde(default)]
+    pub mobike: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Default)]
@@ -28,3 +52,88 @@ pub struct Experimental {
     #[serde(default)]
     pub iptfs: bool,
 }
+
+impl Config {
+    /// Checks that identities of all endpoints can be encoded.
+    pub fn validate(&self) -> Result<(), Error> {
+        for endpoint in &self.endpoints {
+            asn::validate_identity(
+                &self.organization,
+                &self.common_name,
+                &endpoint.serial_number,
+                endpoint.serial_number_encoding,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Bounds on vici operations, in seconds.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// any request without a more specific timeout
+    pub request: u64,
+    /// the peer acknowledging the deletion of an sa
+    pub terminate: u64,
+    /// a whole reconciliation
+    pub reconcile: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            request: 10,
+            terminate: 5,
+            reconcile: 300,
+        }
+    }
+}
+
+/// How the local node authenticates itself to peers.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Auth {
+    /// raw public key, pinned by peers through the registry
+    #[default]
+    Pubkey,
+    /// certificate with a subject matching the endpoint identity, issued by the organization ca
+    Certificate,
+}
+
+/// Connection parameters, see https://docs.strongswan.org/docs/5.9/swanctl/swanctlConf.html
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Parameters {
+    pub version: u32,
+    pub encap: bool,
+    pub mobike: bool,
+    pub dpd_delay: u64,
+    pub keyingtries: u32,
+    pub unique: String,
+    pub rekey_time: Option<u64>,
+    pub reauth_time: Option<u64>,
+    pub over_time: Option<u64>,
+    pub dpd_action: String,
+    pub start_action: String,
+    pub close_action: String,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self {
+            version: 2,
+            encap: true,
+            mobike: false,
+            dpd_delay: 10,
+            keyingtries: 0,
+            unique: "replace".to_string(),
+            rekey_time: None,
+            reauth_time: None,
+            over_time: None,
+            dpd_action: "restart".to_string(),
+            start_action: "none".to_string(),
+            close_action: "none".to_string(),
+        }
+    }
+}
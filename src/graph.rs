@@ -0,0 +1,306 @@
+use crate::{
+    config::Config,
+    reconciler::DesiredState,
+    registry::Registry,
+    topology::{self, Role},
+    vici::SAs,
+};
+use serde::Serialize;
+use std::{collections::HashMap, fmt::Write};
+
+/// The mesh as planned by the topology, optionally with the live state of the local node.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Node {
+    pub organization: String,
+    pub common_name: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Hash, Clone)]
+pub struct End {
+    pub organization: String,
+    pub common_name: String,
+    pub serial_number: String,
+}
+
+/// A connection between two endpoints sharing an address family.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Edge {
+    pub from: End,
+    pub to: End,
+    pub address_family: String,
+    pub state: State,
+}
+
+#[derive(Debug, Serialize, PartialEq, PartialOrd, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// called for by the topology, state unknown or not yet loaded
+    Planned,
+    /// an ike sa exists but is not established
+    Connecting,
+    Established,
+}
+
+/// Live state of the edges of the local node.
+pub type Live = HashMap<(End, End), State>;
+
+/// Matches the sas of the local node to edges by the connection names `plan` assigned.
+pub fn live(config: &Config, state: &DesiredState, sas: &SAs) -> Live {
+    let mut live = Live::new();
+    for connection in &state.connections {
+        let Some(sa) = sas.get(&connection.name) else {
+            continue;
+        };
+        let state = if sa.state == "ESTABLISHED" {
+            State::Established
+        } else {
+            State::Connecting
+        };
+        let local = End {
+            organization: config.organization.clone(),
+            common_name: config.common_name.clone(),
+            serial_number: connection.peer.local_serial_number.clone(),
+        };
+        let remote = End {
+            organization: connection.peer.organization.clone(),
+            common_name: connection.peer.common_name.clone(),
+            serial_number: connection.peer.remote_serial_number.clone(),
+        };
+        live.insert((local, remote), state);
+    }
+    live
+}
+
+/// Builds the graph of the whole registry, regardless of local policies.
+///
+/// With the live state of the local node, edges of that node carry it.
+pub fn graph(registry: &Registry, live: Option<&Live>) -> Graph {
+    let nodes: Vec<_> = topology::nodes(registry).collect();
+    let hubs = topology::Hubs::new(registry);
+
+    let mut edges = vec![];
+    for (i, (a, a_node)) in nodes.iter().enumerate() {
+        for (b, b_node) in &nodes[i + 1..] {
+            if !topology::connected(a, b, &hubs) {
+                continue;
+            }
+            for a_endpoint in &a_node.endpoints {
+                for b_endpoint in b_node
+                    .endpoints
+                    .iter()
+                    .filter(|endpoint| endpoint.address_family == a_endpoint.address_family)
+                {
+                    let from = End {
+                        organization: a.organization.to_string(),
+                        common_name: a.common_name.to_string(),
+                        serial_number: a_endpoint.serial_number.clone(),
+                    };
+                    let to = End {
+                        organization: b.organization.to_string(),
+                        common_name: b.common_name.to_string(),
+                        serial_number: b_endpoint.serial_number.clone(),
+                    };
+                    // sas are only known from the side of the local node
+                    let state = live
+                        .and_then(|live| {
+                            live.get(&(from.clone(), to.clone()))
+                                .or_else(|| live.get(&(to.clone(), from.clone())))
+                        })
+                        .copied()
+                        .unwrap_or(State::Planned);
+                    edges.push(Edge {
+                        from,
+                        to,
+                        address_family: a_endpoint.address_family.clone(),
+                        state,
+                    });
+                }
+            }
+        }
+    }
+
+    Graph {
+        nodes: nodes
+            .iter()
+            .map(|(member, _)| Node {
+                organization: member.organization.to_string(),
+                common_name: member.common_name.to_string(),
+                role: member.topology.role,
+            })
+            .collect(),
+        edges,
+    }
+}
+
+impl Graph {
+    /// Renders the graph in the Graphviz DOT language, clustering nodes by organization.
+    pub fn to_dot(&self) -> String {
+        let quote =
+            |value: &str| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""));
+        let id = |organization: &str, common_name: &str| {
+            quote(&format!("{}/{}", organization, common_name))
+        };
+
+        let mut organizations: Vec<&str> = vec![];
+        for node in &self.nodes {
+            if !organizations.contains(&node.organization.as_str()) {
+                organizations.push(&node.organization);
+            }
+        }
+
+        let mut dot = String::from("graph ranet {\n");
+        for (i, organization) in organizations.iter().enumerate() {
+            let _ = writeln!(dot, "  subgraph cluster_{} {{", i);
+            let _ = writeln!(dot, "    label = {};", quote(organization));
+            for node in self
+                .nodes
+                .iter()
+                .filter(|node| node.organization == *organization)
+            {
+                let _ = writeln!(
+                    dot,
+                    "    {} [label = {}, shape = {}];",
+                    id(&node.organization, &node.common_name),
+                    quote(&node.common_name),
+                    match node.role {
+                        Role::Mesh => "ellipse",
+                        Role::Hub => "doublecircle",
+                        Role::Spoke => "box",
+                    }
+                );
+            }
+            dot.push_str("  }\n");
+        }
+        for edge in &self.edges {
+            let _ = writeln!(
+                dot,
+                "  {} -- {} [label = {}, style = {}];",
+                id(&edge.from.organization, &edge.from.common_name),
+                id(&edge.to.organization, &edge.to.common_name),
+                quote(&edge.address_family),
+                match edge.state {
+                    State::Planned => "dotted",
+                    State::Connecting => "dashed",
+                    State::Established => "solid",
+                }
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reconciler;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn graph() {
+        let registry: Registry = serde_json::from_value(serde_json::json!([
+            {
+                "public_key": "<PEM>",
+                "organization": "acme",
+                "nodes": [
+                    {
+                        "common_name": "hub0",
+                        "endpoints": [
+                            { "serial_number": "0", "address_family": "ip4", "port": 3000 },
+                            { "serial_number": "1", "address_family": "ip6", "port": 3000 }
+                        ],
+                        "topology": { "role": "hub" }
+                    },
+                    {
+                        "common_name": "spoke0",
+                        "endpoints": [{ "serial_number": "0", "address_family": "ip4", "port": 3000 }],
+                        "topology": { "role": "spoke" }
+                    }
+                ]
+            },
+            {
+                "public_key": "<PEM>",
+                "organization": "nickcao",
+                "nodes": [
+                    {
+                        "common_name": "nrt0",
+                        "endpoints": [{ "serial_number": "0", "address_family": "ip6", "port": 3000 }]
+                    }
+                ]
+            }
+        ]))
+        .unwrap();
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "organization": "acme",
+            "common_name": "hub0",
+            "endpoints": [
+                { "serial_number": "0", "address_family": "ip4", "address": null, "port": 3000, "updown": null, "fwmark": null },
+                { "serial_number": "1", "address_family": "ip6", "address": null, "port": 3000, "updown": null, "fwmark": null }
+            ],
+            "topology": { "role": "hub" }
+        }))
+        .unwrap();
+        let state = reconciler::plan(
+            &config,
+            &registry,
+            "<LOCAL PEM>",
+            OffsetDateTime::now_utc(),
+            |_, _| None,
+        )
+        .unwrap();
+        let name = |common_name: &str| {
+            state
+                .connections
+                .iter()
+                .find(|connection| connection.peer.common_name == common_name)
+                .unwrap()
+                .name
+                .clone()
+        };
+
+        // as printed by list-sas, with identities in dn form
+        let sas: SAs = serde_json::from_value(serde_json::json!({
+            name("spoke0"): {
+                "uniqueid": "1",
+                "version": "2",
+                "state": "ESTABLISHED",
+                "local-host": "192.0.2.1",
+                "local-port": "4500",
+                "local-id": "O=acme, CN=hub0, serialNumber=0",
+                "remote-host": "192.0.2.2",
+                "remote-port": "4500",
+                "remote-id": "O=acme, CN=spoke0, serialNumber=0",
+                "child-sas": {}
+            },
+            name("nrt0"): {
+                "uniqueid": "2",
+                "version": "2",
+                "state": "CONNECTING",
+                "local-id": "O=acme, CN=hub0, serialNumber=1",
+                "remote-id": "O=nickcao, CN=nrt0, serialNumber=0",
+                "tasks-active": ["IKE_INIT"]
+            },
+            "foreign": {
+                "uniqueid": "3",
+                "version": "2",
+                "state": "ESTABLISHED",
+                "local-id": "O=acme, CN=hub0, serialNumber=0",
+                "remote-id": "O=nickcao, CN=nrt0, serialNumber=0"
+            }
+        }))
+        .unwrap();
+
+        let live = super::live(&config, &state, &sas);
+        let graph = super::graph(&registry, Some(&live));
+        insta::assert_yaml_snapshot!(graph);
+        insta::assert_snapshot!(graph.to_dot());
+    }
+}
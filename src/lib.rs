@@ -1,16 +1,22 @@
-use base64::prelude::{Engine, BASE64_URL_SAFE};
 use config::Config;
-use core::str;
 use registry::Registry;
-use sha2::{Digest, Sha256};
-use std::collections::HashSet;
-use tracing::{debug, debug_span, info, warn};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, debug_span};
 
 pub mod address;
 pub mod asn;
+pub mod capability;
+pub mod cert;
 pub mod config;
+pub mod graph;
 pub mod key;
+pub mod metrics;
+pub mod policy;
+pub mod reconciler;
 pub mod registry;
+pub mod relay;
+pub mod topology;
 pub mod vici;
 
 pub mod error {
@@ -32,130 +38,114 @@ pub mod error {
         #[error("utf8 error")]
         Utf8(#[from] Utf8Error),
         #[error("pkcs8 error")]
-        Openssl(#[from] ed25519_dalek::pkcs8::Error),
+        Openssl(#[from] pkcs8::Error),
+        #[error("spki error")]
+        Spki(#[from] pkcs8::spki::Error),
         #[error("serde json error")]
         Json(#[from] serde_json::Error),
+        #[error("prometheus error")]
+        Prometheus(#[from] prometheus::Error),
+        #[error("random error")]
+        Random(#[from] getrandom::Error),
+        #[error("rsa pkcs8 error")]
+        Rsa(#[from] rsa::pkcs8::Error),
+        #[error("unsupported key type {0}, supported are PKCS#8 encoded ed25519, ecdsa p-256, ecdsa p-384, rsa and, with the ed448 feature, ed448 keys")]
+        UnsupportedKey(String),
+        #[error("key not found at {0}")]
+        MissingKey(String),
+        #[error("invalid identity {0}")]
+        InvalidIdentity(String),
+        #[error("der error")]
+        Der(#[from] x509_cert::der::Error),
+        #[error("certificate error")]
+        Certificate(#[from] x509_cert::builder::Error),
+        #[error("charon does not support {0}")]
+        Unsupported(String),
+        #[error("vici {command} timed out{}", peer.as_ref().map(|peer| format!(" for {}", peer)).unwrap_or_default())]
+        Timeout {
+            command: String,
+            peer: Option<String>,
+        },
+        #[error("reconcile did not finish within {0} seconds")]
+        Deadline(u64),
     }
-}
 
-fn generate_name(existing: &HashSet<String>, data: &[u8]) -> String {
-    let mut data = data.to_vec();
-    loop {
-        let name = BASE64_URL_SAFE.encode(&Sha256::digest(&data)[..3]);
-        if existing.contains(&name) {
-            data.push(0);
-        } else {
-            return name;
+    impl Error {
+        pub fn kind(&self) -> &'static str {
+            match self {
+                Error::IO(_) => "io",
+                Error::Vici(_) => "vici",
+                Error::Semver(_) => "semver",
+                Error::Protocol(_) => "protocol",
+                Error::FromUtf8(_) | Error::Utf8(_) => "utf8",
+                Error::Openssl(_)
+                | Error::Spki(_)
+                | Error::Rsa(_)
+                | Error::UnsupportedKey(_)
+                | Error::MissingKey(_) => "key",
+                Error::Json(_) => "json",
+                Error::Prometheus(_) => "prometheus",
+                Error::Random(_) => "random",
+                Error::Der(_) | Error::Certificate(_) => "certificate",
+                Error::InvalidIdentity(_) => "identity",
+                Error::Unsupported(_) => "unsupported",
+                Error::Timeout { .. } | Error::Deadline(_) => "timeout",
+            }
         }
     }
 }
 
+/// A desired connection, labelled with the registry names of its remote end.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Peer {
+    pub organization: String,
+    pub common_name: String,
+    pub local_serial_number: String,
+    pub remote_serial_number: String,
+}
+
+/// Outcome of a single reconciliation.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// desired connections, keyed by connection name
+    pub peers: HashMap<String, Peer>,
+    /// connections successfully loaded into charon
+    pub loaded: HashSet<String>,
+    /// connections this node is the designated initiator of, the other end initiates the rest
+    pub initiator: HashSet<String>,
+    /// non-fatal errors encountered, by kind
+    pub errors: Vec<&'static str>,
+    /// connections whose remote address could not be resolved
+    pub dns_failures: HashSet<String>,
+    /// connections neither end can initiate, as neither has an address to dial
+    pub unreachable: HashSet<String>,
+    /// relays for unreachable connections, if any node can reach both ends
+    pub relays: HashMap<String, relay::Relay>,
+}
+
+/// Reconciles charon at the vici socket with the config and registry.
+///
+/// `loaded` carries the keys ranet loaded from one run to the next, it is updated even on failure.
 pub async fn reconcile(
     socket: &str,
     config: &Config,
     registry: &Registry,
-    key: &[u8],
-) -> Result<(), error::Error> {
+    key: &key::Source,
+    certificates: &[String],
+    loaded: &mut reconciler::Loaded,
+) -> Result<Report, error::Error> {
     let _span_reconcile = debug_span!("reconcile").entered();
 
-    let mut client = vici::Client::connect(socket).await?;
+    let client = vici::Client::connect(socket).await?;
 
     debug!("connected to vici socket");
 
-    client.load_key(key).await?;
-
-    debug!("loaded private key");
-
-    let public_key = key::private_key_to_public(str::from_utf8(key)?)?;
-
-    debug!("derived public key");
-
-    let mut desired = HashSet::<String>::default();
-
-    for local in &config.endpoints {
-        let _span_local = debug_span!("local").entered();
-
-        let local_id = asn::encode_identity(
-            &config.organization,
-            &config.common_name,
-            &local.serial_number,
-        )
-        .unwrap();
-
-        debug!(
-            "encoded local_id {} {} {}",
-            config.organization, config.common_name, local.serial_number
-        );
-
-        let local_addrs = address::local(&local.address_family, &local.address);
-        for organization in registry {
-            let _span_organization = debug_span!("org", name = organization.organization).entered();
-
-            for node in &organization.nodes {
-                let _span_node = debug_span!("node", cn = node.common_name).entered();
-
-                if node.common_name == config.common_name {
-                    continue;
-                }
-                for remote in &node.endpoints {
-                    let _span_endpoint =
-                        debug_span!("endpoint", sn = remote.serial_number).entered();
-
-                    if remote.address_family != local.address_family {
-                        continue;
-                    }
-
-                    let remote_id = asn::encode_identity(
-                        &organization.organization,
-                        &node.common_name,
-                        &remote.serial_number,
-                    )
-                    .unwrap();
-
-                    let remote_addrs = address::remote(&remote.address_family, &remote.address);
-                    let name =
-                        generate_name(&desired, format!("{}-{}", &local_id, &remote_id).as_bytes());
-                    desired.insert(name.clone());
-                    let result = client
-                        .load_conn(
-                            &name,
-                            vici::Endpoint {
-                                id: local_id.clone(),
-                                addrs: local_addrs.clone(),
-                                port: local.port,
-                                pubkey: public_key.clone(),
-                            },
-                            vici::Endpoint {
-                                id: remote_id,
-                                addrs: remote_addrs,
-                                port: remote.port,
-                                pubkey: organization.public_key.clone(),
-                            },
-                            local.updown.clone(),
-                            local.fwmark.clone(),
-                            config.experimental.iptfs,
-                        )
-                        .await;
-
-                    if let Err(err) = result {
-                        warn!("load connection error: {}", err);
-                        continue;
-                    }
-
-                    info!("loaded connection");
-
-                    client.initiate(&name).await?;
-                }
-            }
-        }
-    }
-
-    let current = HashSet::<String>::from_iter(client.get_conns().await?.into_iter());
-
-    for conn in current.difference(&desired) {
-        client.unload_conn(conn).await?;
-        client.terminate(conn).await?;
-    }
-
-    Ok(())
+    let mut reconciler =
+        reconciler::Reconciler::new(Box::new(client.with_timeouts(config.timeouts)))
+            .with_loaded(std::mem::take(loaded));
+    let result = reconciler
+        .reconcile(config, registry, key, certificates)
+        .await;
+    *loaded = reconciler.into_loaded();
+    result
 }
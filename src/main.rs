@@ -1,7 +1,27 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, net::SocketAddr, sync::Arc, time::Duration};
 
-use clap::{Parser, Subcommand};
-use ranet::{config::Config, reconcile, registry::Registry};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use ed25519_dalek::{pkcs8::DecodePrivateKey, SigningKey};
+use ranet::{
+    address, asn,
+    capability::Capabilities,
+    cert,
+    config::{Config, Timeouts},
+    graph, key,
+    metrics::Metrics,
+    reconcile,
+    reconciler::{self, Loaded, Reconciler},
+    registry::{self, Organization, Registry},
+    relay, topology,
+    vici::{self, Transport},
+};
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    time::Instant,
+};
+use tracing::{error, warn};
 use tracing_subscriber::EnvFilter;
 
 /// ranet
@@ -10,49 +30,469 @@ use tracing_subscriber::EnvFilter;
 struct Args {
     /// path to config file
     #[arg(short, long)]
-    config: String,
+    config: Option<String>,
     /// path to registry file
     #[arg(short, long)]
-    registry: String,
-    /// path to private key
+    registry: Option<String>,
+    /// path to private key, or to the public key when using a token,
+    /// also accepts env:NAME, cred:NAME for systemd credentials and - for stdin
     #[arg(short, long)]
-    key: String,
+    key: Option<String>,
+    /// certificates to load, one per endpoint when using certificate authentication
+    #[arg(long = "cert")]
+    certs: Vec<String>,
+    /// hex encoded identifier of a private key on a PKCS#11 token
+    #[arg(long)]
+    token_handle: Option<String>,
+    /// slot of the token
+    #[arg(long, requires = "token_handle")]
+    token_slot: Option<u32>,
+    /// PKCS#11 module providing the token
+    #[arg(long, requires = "token_handle")]
+    token_module: Option<String>,
+    /// pin of the token, same syntax as --key
+    #[arg(long, requires = "token_handle")]
+    token_pin: Option<String>,
     /// path to vici control socket
     #[arg(short, long, default_value = "/run/charon.vici")]
     vici: String,
+    /// path to the file tracking the private keys ranet loaded into charon
+    #[arg(long, default_value = "/run/ranet.json")]
+    state: String,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    Up,
-    Down,
+    Up {
+        /// serve prometheus metrics on this address, reconciling periodically
+        #[arg(long)]
+        metrics: Option<SocketAddr>,
+        /// seconds between reconciliations when serving metrics
+        #[arg(long, default_value_t = 60)]
+        interval: u64,
+        /// write relay hints for unreachable peers to this file as json
+        #[arg(long)]
+        relays: Option<String>,
+    },
+    /// terminate the sas of all connections, then unload them
+    Down {
+        /// seconds each peer is given to acknowledge the delete, overriding timeouts.terminate
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// send deletes to this many peers at once, each over its own vici connection,
+        /// 4 when given without a value
+        #[arg(long, default_value_t = 1, num_args = 0..=1, default_missing_value = "4")]
+        parallel: usize,
+    },
+    /// generate an ed25519 private key
+    Keygen,
+    /// print the public key
+    Pubkey {
+        /// print a registry organization entry for the local node instead
+        #[arg(long)]
+        stub: bool,
+    },
+    /// print the charon version, its plugins and the ike sas
+    Status,
+    /// print the number of edges and connections of each node in the registry topology
+    Plan,
+    /// print the mesh planned by the registry topology
+    Graph {
+        #[arg(long, value_enum, default_value_t = Format::Dot)]
+        format: Format,
+        /// annotate edges of the local node with the state of its sas, requires --config
+        #[arg(long)]
+        live: bool,
+    },
+    /// decode asn1dn identities, or identities in log lines read from stdin
+    Decode { identities: Vec<String> },
+    /// manage certificates signed by the organization key
+    Cert {
+        #[command(subcommand)]
+        command: CertCommands,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<(), ranet::error::Error> {
+#[derive(ValueEnum, Clone, Debug)]
+enum Format {
+    /// graphviz dot
+    Dot,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum CertCommands {
+    /// print a self-signed ca certificate for the organization
+    Ca {
+        #[arg(long)]
+        organization: String,
+        /// validity in days
+        #[arg(long, default_value_t = 3650)]
+        days: u64,
+    },
+    /// print a certificate for a node endpoint
+    Issue {
+        #[arg(long)]
+        organization: String,
+        #[arg(long)]
+        common_name: String,
+        #[arg(long)]
+        serial_number: String,
+        /// encode the serial number as UTF8String instead of PrintableString
+        #[arg(long)]
+        utf8_serial_number: bool,
+        /// public key of the node, same syntax as --key
+        #[arg(long)]
+        public_key: String,
+        /// validity in days
+        #[arg(long, default_value_t = 7)]
+        days: u64,
+    },
+    /// print the content of a certificate
+    Inspect {
+        /// certificate, same syntax as --key
+        certificate: String,
+    },
+}
+
+async fn cert(args: &Args, command: &CertCommands) -> Result<(), ranet::error::Error> {
+    let signing_key = || async {
+        let key = key::read(required(&args.key, "key")).await?;
+        Ok::<_, ranet::error::Error>(SigningKey::from_pkcs8_pem(std::str::from_utf8(&key)?)?)
+    };
+    let days = |days: u64| Duration::from_secs(days * 24 * 60 * 60);
+
+    match command {
+        CertCommands::Ca {
+            organization,
+            days: validity,
+        } => {
+            print!(
+                "{}",
+                cert::issue_ca(organization, &signing_key().await?, days(*validity))?
+            );
+        }
+        CertCommands::Issue {
+            organization,
+            common_name,
+            serial_number,
+            utf8_serial_number,
+            public_key,
+            days: validity,
+        } => {
+            let public_key = String::from_utf8(key::read(public_key).await?.to_vec())?;
+            print!(
+                "{}",
+                cert::issue(
+                    organization,
+                    common_name,
+                    serial_number,
+                    if *utf8_serial_number {
+                        asn::Encoding::Utf8
+                    } else {
+                        asn::Encoding::Printable
+                    },
+                    &public_key,
+                    &signing_key().await?,
+                    days(*validity),
+                )?
+            );
+        }
+        CertCommands::Inspect { certificate } => {
+            let certificate = String::from_utf8(key::read(certificate).await?.to_vec())?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&cert::inspect(&certificate)?)?
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn required<'a>(value: &'a Option<String>, name: &str) -> &'a str {
+    value.as_deref().unwrap_or_else(|| {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                format!("--{} is required for this command", name),
+            )
+            .exit()
+    })
+}
+
+async fn load_config(args: &Args) -> Result<Config, ranet::error::Error> {
+    let config = tokio::fs::read(required(&args.config, "config")).await?;
+    let config: Config = serde_json::from_slice(&config)?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Reads the registry, skipping endpoints that cannot be peered with along with the kinds of
+/// their errors.
+async fn load_registry(args: &Args) -> Result<(Registry, Vec<&'static str>), ranet::error::Error> {
+    let registry = tokio::fs::read(required(&args.registry, "registry")).await?;
+    let mut registry: Registry = serde_json::from_slice(&registry)?;
+    let errors = registry::validate(&mut registry)
+        .into_iter()
+        .map(|err| {
+            warn!("skipping registry endpoint: {}", err);
+            err.kind()
+        })
+        .collect();
+    Ok((registry, errors))
+}
+
+async fn read_key(args: &Args) -> Result<key::Source, ranet::error::Error> {
+    let key = key::read(required(&args.key, "key")).await?;
+    let pin = match &args.token_pin {
+        Some(location) => Some(key::read_secret(location).await?),
+        None => None,
+    };
+    Ok(match &args.token_handle {
+        None => key::Source::Private(key),
+        Some(handle) => key::Source::Token(key::Token {
+            handle: handle.clone(),
+            slot: args.token_slot,
+            module: args.token_module.clone(),
+            pin,
+            public_key: String::from_utf8(key.to_vec())?,
+        }),
+    })
+}
+
+async fn read_certs(args: &Args) -> Result<Vec<String>, ranet::error::Error> {
+    let mut certs = vec![];
+    for location in &args.certs {
+        certs.push(String::from_utf8(key::read(location).await?.to_vec())?);
+    }
+    Ok(certs)
+}
+
+async fn up(
+    args: &Args,
+    config: &Config,
+    key: &key::Source,
+    certs: &[String],
+) -> Result<ranet::Report, ranet::error::Error> {
+    let (registry, errors) = load_registry(args).await?;
+
+    let mut loaded = Loaded::read(&args.state).await?;
+    let result = reconcile(&args.vici, config, &registry, key, certs, &mut loaded).await;
+    loaded.write(&args.state).await?;
+    let mut report = result?;
+    report.errors.extend(errors);
+
+    if let Commands::Up {
+        relays: Some(path), ..
+    } = &args.command
+    {
+        tokio::fs::write(path, serde_json::to_string_pretty(&relay::hints(&report))?).await?;
+    }
+
+    Ok(report)
+}
+
+/// Key locations the command may read.
+fn locations(args: &Args) -> Vec<&str> {
+    let mut locations: Vec<&str> = args
+        .key
+        .iter()
+        .chain(&args.token_pin)
+        .chain(&args.certs)
+        .map(String::as_str)
+        .collect();
+    match &args.command {
+        Commands::Cert {
+            command: CertCommands::Issue { public_key, .. },
+        } => locations.push(public_key),
+        Commands::Cert {
+            command: CertCommands::Inspect { certificate },
+        } => locations.push(certificate),
+        _ => {}
+    }
+    locations
+}
+
+fn main() -> Result<(), ranet::error::Error> {
     tracing_subscriber::fmt::fmt()
         .pretty()
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
     let args = Args::parse();
+    // still single threaded, the runtime is built below
+    key::take_env(locations(&args));
 
-    let config = tokio::fs::read(&args.config).await?;
-    let config: Config = serde_json::from_slice(&config)?;
-
-    let key = tokio::fs::read(&args.key).await?;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
 
+async fn run(args: Args) -> Result<(), ranet::error::Error> {
     match &args.command {
-        Commands::Up => {
-            let registry = tokio::fs::read(&args.registry).await?;
-            let registry: Registry = serde_json::from_slice(&registry)?;
+        Commands::Keygen => {
+            let private_key = key::generate_private_key()?;
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(required(&args.key, "key"))
+                .await?;
+            file.write_all(private_key.as_bytes()).await?;
+        }
+        Commands::Cert { command } => cert(&args, command).await?,
+        Commands::Plan => {
+            let (registry, _) = load_registry(&args).await?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&topology::plan(&registry))?
+            );
+        }
+        Commands::Graph { format, live } => {
+            let (registry, _) = load_registry(&args).await?;
+            let live = if *live {
+                let config = load_config(&args).await?;
+                // connection names only depend on identities, not on the local public key
+                let state = reconciler::plan(
+                    &config,
+                    &registry,
+                    "",
+                    OffsetDateTime::now_utc(),
+                    address::resolve,
+                )?;
+                let mut client = vici::Client::connect(&args.vici).await?;
+                let sas = client.list_sas(None).await?;
+                Some(graph::live(&config, &state, &sas))
+            } else {
+                None
+            };
+            let graph = graph::graph(&registry, live.as_ref());
+            match format {
+                Format::Dot => print!("{}", graph.to_dot()),
+                Format::Json => println!("{}", serde_json::to_string_pretty(&graph)?),
+            }
+        }
+        Commands::Status => {
+            let mut client = vici::Client::connect(&args.vici).await?;
+            let capabilities = Capabilities::query(&mut client).await?;
+            let mut sas: Vec<_> = client.list_sas(None).await?.into_iter().collect();
+            sas.sort_by(|a, b| a.0.cmp(&b.0));
+            let decode = |id: &Option<String>| {
+                id.as_deref()
+                    .map(asn::decode_identities)
+                    .unwrap_or_default()
+            };
+            let sas: Vec<_> = sas
+                .iter()
+                .map(|(name, sa)| {
+                    serde_json::json!({
+                        "name": name,
+                        "state": sa.state,
+                        "local_id": decode(&sa.local_id),
+                        "remote_id": decode(&sa.remote_id),
+                        "child_sas": sa.child_sas.len(),
+                    })
+                })
+                .collect();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "charon": capabilities,
+                    "sas": sas,
+                }))?
+            );
+        }
+        Commands::Down { timeout, parallel } => {
+            let mut timeouts = match &args.config {
+                Some(_) => load_config(&args).await?.timeouts,
+                None => Timeouts::default(),
+            };
+            if let Some(timeout) = timeout {
+                timeouts.terminate = *timeout;
+            }
+            let client = vici::Client::connect(&args.vici)
+                .await?
+                .with_timeouts(timeouts);
+            let mut reconciler =
+                Reconciler::new(Box::new(client)).with_loaded(Loaded::read(&args.state).await?);
+            let result = reconciler.down(*parallel).await;
+            reconciler.into_loaded().write(&args.state).await?;
+            let teardown = result?;
+            println!("{}", serde_json::to_string_pretty(&teardown)?);
+        }
+        Commands::Decode { identities } => {
+            if identities.is_empty() {
+                let mut lines = BufReader::new(tokio::io::stdin()).lines();
+                while let Some(line) = lines.next_line().await? {
+                    println!("{}", asn::decode_identities(&line));
+                }
+            }
+            for identity in identities {
+                println!("{}", asn::decode_identity(identity)?);
+            }
+        }
+        Commands::Up {
+            metrics, interval, ..
+        } => {
+            let key = read_key(&args).await?;
+            let certs = read_certs(&args).await?;
+            let config = load_config(&args).await?;
+            let Some(listen) = metrics else {
+                up(&args, &config, &key, &certs).await?;
+                return Ok(());
+            };
+
+            let metrics = Arc::new(Metrics::new()?);
+            let serve = ranet::metrics::serve(TcpListener::bind(listen).await?, metrics.clone());
+
+            let reconcile = async {
+                loop {
+                    let start = Instant::now();
+                    let result = up(&args, &config, &key, &certs).await;
+                    metrics.observe_duration(start.elapsed());
+
+                    match result {
+                        Ok(report) => {
+                            metrics.observe_report(&report);
+                            let client = vici::Client::connect(&args.vici).await;
+                            match client.map(|client| client.with_timeouts(config.timeouts)) {
+                                Ok(mut client) => match client.list_sas(None).await {
+                                    Ok(sas) => metrics.observe_sas(&report, &sas),
+                                    Err(err) => metrics.observe_error(&err),
+                                },
+                                Err(err) => metrics.observe_error(&err),
+                            }
+                        }
+                        Err(err) => {
+                            error!("reconcile error: {}", err);
+                            metrics.observe_error(&err);
+                        }
+                    }
+
+                    tokio::time::sleep(Duration::from_secs(*interval)).await;
+                }
+            };
 
-            reconcile(&args.vici, &config, &registry, &key).await?;
+            // the server only returns on failure
+            tokio::select! {
+                result = serve => result?,
+                _ = reconcile => {}
+            }
         }
-        Commands::Down => {
-            reconcile(&args.vici, &config, &vec![], &key).await?;
+        Commands::Pubkey { stub } => {
+            let key = read_key(&args).await?;
+            let public_key = key.public_key()?;
+            if *stub {
+                let config = load_config(&args).await?;
+                let organization = Organization::stub(&config, public_key);
+                println!("{}", serde_json::to_string_pretty(&organization)?);
+            } else {
+                print!("{}", public_key);
+            }
         }
     }
 
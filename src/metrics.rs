@@ -0,0 +1,294 @@
+use crate::{error::Error, vici::SAs, Report};
+use prometheus::{Gauge, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::warn;
+
+pub struct Metrics {
+    registry: Registry,
+    desired_connections: IntGauge,
+    loaded_connections: IntGauge,
+    unreachable_connections: IntGauge,
+    ike_sas: IntGaugeVec,
+    child_sas: IntGaugeVec,
+    bytes: IntGaugeVec,
+    packets: IntGaugeVec,
+    reconcile_duration: Gauge,
+    reconcile_errors: IntCounterVec,
+    dns_failures: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, Error> {
+        let registry = Registry::new_custom(Some("ranet".to_string()), None)?;
+
+        let desired_connections =
+            IntGauge::new("desired_connections", "connections ranet wants loaded")?;
+        let loaded_connections =
+            IntGauge::new("loaded_connections", "connections successfully loaded")?;
+        let unreachable_connections = IntGauge::new(
+            "unreachable_connections",
+            "connections neither end has an address to dial",
+        )?;
+        let ike_sas = IntGaugeVec::new(
+            Opts::new("established_ike_sas", "established ike sas"),
+            &["organization"],
+        )?;
+        let child_sas = IntGaugeVec::new(
+            Opts::new("installed_child_sas", "installed child sas"),
+            &["organization"],
+        )?;
+        let bytes = IntGaugeVec::new(
+            Opts::new("child_sa_bytes", "bytes processed by child sas"),
+            &["organization", "common_name", "direction"],
+        )?;
+        let packets = IntGaugeVec::new(
+            Opts::new("child_sa_packets", "packets processed by child sas"),
+            &["organization", "common_name", "direction"],
+        )?;
+        let reconcile_duration = Gauge::new(
+            "reconcile_duration_seconds",
+            "duration of the last reconciliation",
+        )?;
+        let reconcile_errors = IntCounterVec::new(
+            Opts::new("reconcile_errors_total", "reconciliation errors"),
+            &["kind"],
+        )?;
+        let dns_failures = IntCounterVec::new(
+            Opts::new("dns_failures_total", "remote address resolution failures"),
+            &["organization", "common_name"],
+        )?;
+
+        registry.register(Box::new(desired_connections.clone()))?;
+        registry.register(Box::new(loaded_connections.clone()))?;
+        registry.register(Box::new(unreachable_connections.clone()))?;
+        registry.register(Box::new(ike_sas.clone()))?;
+        registry.register(Box::new(child_sas.clone()))?;
+        registry.register(Box::new(bytes.clone()))?;
+        registry.register(Box::new(packets.clone()))?;
+        registry.register(Box::new(reconcile_duration.clone()))?;
+        registry.register(Box::new(reconcile_errors.clone()))?;
+        registry.register(Box::new(dns_failures.clone()))?;
+
+        Ok(Self {
+            registry,
+            desired_connections,
+            loaded_connections,
+            unreachable_connections,
+            ike_sas,
+            child_sas,
+            bytes,
+            packets,
+            reconcile_duration,
+            reconcile_errors,
+            dns_failures,
+        })
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.reconcile_duration.set(duration.as_secs_f64());
+    }
+
+    pub fn observe_error(&self, err: &Error) {
+        self.reconcile_errors.with_label_values(&[err.kind()]).inc();
+    }
+
+    pub fn observe_report(&self, report: &Report) {
+        self.desired_connections.set(report.peers.len() as i64);
+        self.loaded_connections.set(report.loaded.len() as i64);
+        self.unreachable_connections
+            .set(report.unreachable.len() as i64);
+
+        for kind in &report.errors {
+            self.reconcile_errors.with_label_values(&[*kind]).inc();
+        }
+
+        for peer in report
+            .dns_failures
+            .iter()
+            .filter_map(|name| report.peers.get(name))
+        {
+            self.dns_failures
+                .with_label_values(&[&peer.organization, &peer.common_name])
+                .inc();
+        }
+    }
+
+    pub fn observe_sas(&self, report: &Report, sas: &SAs) {
+        self.ike_sas.reset();
+        self.child_sas.reset();
+        self.bytes.reset();
+        self.packets.reset();
+
+        for (name, sa) in sas {
+            // connections not managed by ranet are of no interest
+            let Some(peer) = report.peers.get(name) else {
+                continue;
+            };
+
+            let organization = peer.organization.as_str();
+            let common_name = peer.common_name.as_str();
+
+            if sa.state == "ESTABLISHED" {
+                self.ike_sas.with_label_values(&[organization]).inc();
+            }
+
+            for child in sa.child_sas.values() {
+                if child.state == "INSTALLED" {
+                    self.child_sas.with_label_values(&[organization]).inc();
+                }
+                for (direction, bytes, packets) in [
+                    ("in", child.bytes_in, child.packets_in),
+                    ("out", child.bytes_out, child.packets_out),
+                ] {
+                    self.bytes
+                        .with_label_values(&[organization, common_name, direction])
+                        .add(bytes as i64);
+                    self.packets
+                        .with_label_values(&[organization, common_name, direction])
+                        .add(packets as i64);
+                }
+            }
+        }
+    }
+
+    pub fn encode(&self) -> Result<String, Error> {
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> Result<(), Error> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &metrics).await {
+                warn!("metrics request error: {}", err);
+            }
+        });
+    }
+}
+
+/// Longest request head accepted, anything beyond is ignored.
+const MAX_HEAD: u64 = 8192;
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<(), Error> {
+    let mut request = String::new();
+    {
+        let mut reader = BufReader::new((&mut stream).take(MAX_HEAD));
+        reader.read_line(&mut request).await?;
+        // the request line may arrive in pieces, headers are read to their end
+        let mut header = String::new();
+        while reader.read_line(&mut header).await? > 0 && header.trim_end() != "" {
+            header.clear();
+        }
+    }
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.encode()?),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        vici::{ChildSA, SA},
+        Peer, Report,
+    };
+    use std::{collections::HashMap, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(super::serve(
+            listener,
+            Arc::new(super::Metrics::new().unwrap()),
+        ));
+
+        let mut responses = vec![];
+        for pieces in [
+            vec!["GET /met", "rics HTTP/1.1\r\nHost: ranet\r\n", "\r\n"],
+            vec!["GET / HTTP/1.1\r\n\r\n"],
+        ] {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            for piece in pieces {
+                stream.write_all(piece.as_bytes()).await.unwrap();
+                stream.flush().await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            responses.push(response.lines().next().unwrap().to_string());
+        }
+
+        insta::assert_yaml_snapshot!(responses);
+    }
+
+    #[test]
+    fn encode() {
+        let metrics = super::Metrics::new().unwrap();
+
+        let mut report = Report::default();
+        report.peers.insert(
+            "conn".to_string(),
+            Peer {
+                organization: "acme".to_string(),
+                common_name: "server".to_string(),
+                local_serial_number: "0".to_string(),
+                remote_serial_number: "0".to_string(),
+            },
+        );
+        report.loaded.insert("conn".to_string());
+        report.dns_failures.insert("conn".to_string());
+        report.unreachable.insert("conn".to_string());
+        report.errors.push("protocol");
+
+        let sas = HashMap::from([(
+            "conn".to_string(),
+            SA {
+                state: "ESTABLISHED".to_string(),
+                local_id: None,
+                remote_id: None,
+                tasks_active: vec![],
+                child_sas: HashMap::from([(
+                    "default-1".to_string(),
+                    ChildSA {
+                        state: "INSTALLED".to_string(),
+                        bytes_in: 100,
+                        bytes_out: 200,
+                        packets_in: 1,
+                        packets_out: 2,
+                    },
+                )]),
+            },
+        )]);
+
+        metrics.observe_report(&report);
+        metrics.observe_sas(&report, &sas);
+
+        insta::assert_snapshot!(metrics.encode().unwrap());
+    }
+}
@@ -0,0 +1,170 @@
+use crate::registry::Node;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Which registry nodes to peer with, deny rules take precedence over allow rules.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// peer only with nodes matching any of these rules, or with all nodes if empty
+    pub allow: Vec<Rule>,
+    /// never peer with nodes matching any of these rules
+    pub deny: Vec<Rule>,
+}
+
+/// Matches nodes on all of the given fields, patterns may contain `*` and `?`.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    pub organization: Option<String>,
+    pub common_name: Option<String>,
+    /// patterns matched against the remarks of a node, by key
+    pub remarks: BTreeMap<String, String>,
+}
+
+impl Policy {
+    pub fn permits(&self, organization: &str, node: &Node) -> bool {
+        (self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|rule| rule.matches(organization, node)))
+            && !self
+                .deny
+                .iter()
+                .any(|rule| rule.matches(organization, node))
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, organization: &str, node: &Node) -> bool {
+        self.organization
+            .as_ref()
+            .is_none_or(|pattern| glob(pattern, organization))
+            && self
+                .common_name
+                .as_ref()
+                .is_none_or(|pattern| glob(pattern, &node.common_name))
+            && self.remarks.iter().all(|(key, pattern)| {
+                let remark = node.remarks.as_ref().and_then(|remarks| remarks.get(key));
+                match remark {
+                    None => false,
+                    // tags given as a list match if any of them does
+                    Some(serde_json::Value::Array(values)) => {
+                        values.iter().any(|value| remark_matches(pattern, value))
+                    }
+                    Some(value) => remark_matches(pattern, value),
+                }
+            })
+    }
+}
+
+fn remark_matches(pattern: &str, value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::String(value) => glob(pattern, value),
+        serde_json::Value::Null | serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+            false
+        }
+        value => glob(pattern, &value.to_string()),
+    }
+}
+
+/// Matches `*` against any sequence of characters and `?` against a single one.
+fn glob(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+
+    let (mut p, mut v) = (0, 0);
+    // position of the last star in the pattern, and of the value when it was reached
+    let mut backtrack = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    v = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        insta::assert_yaml_snapshot!([
+            super::glob("*", ""),
+            super::glob("nrt*", "nrt0"),
+            super::glob("nrt?", "nrt10"),
+            super::glob("*-eu-*", "node-eu-1"),
+            super::glob("a*b*c", "abxbc"),
+            super::glob("a*b", "abc"),
+        ]);
+    }
+
+    #[test]
+    fn permits() {
+        let policy: Policy = serde_json::from_str(
+            r#"
+            {
+              "allow": [
+                { "remarks": { "region": "eu" } },
+                { "organization": "acme*", "common_name": "hub?" }
+              ],
+              "deny": [
+                { "organization": "evil corp" },
+                { "remarks": { "tags": "draining" } }
+              ]
+            }
+        "#,
+        )
+        .unwrap();
+
+        let node = |common_name: &str, remarks: serde_json::Value| Node {
+            common_name: common_name.to_string(),
+            endpoints: vec![],
+            options: Default::default(),
+            topology: Default::default(),
+            remarks: Some(remarks),
+        };
+
+        insta::assert_yaml_snapshot!([
+            policy.permits(
+                "nickcao",
+                &node("nrt0", serde_json::json!({ "region": "eu" }))
+            ),
+            policy.permits(
+                "nickcao",
+                &node("nrt0", serde_json::json!({ "region": "us" }))
+            ),
+            policy.permits("acme corp", &node("hub0", serde_json::json!({}))),
+            policy.permits("acme corp", &node("spoke0", serde_json::json!({}))),
+            policy.permits(
+                "evil corp",
+                &node("nrt0", serde_json::json!({ "region": "eu" }))
+            ),
+            policy.permits(
+                "nickcao",
+                &node(
+                    "nrt0",
+                    serde_json::json!({ "region": "eu", "tags": ["drain*", "draining"] })
+                )
+            ),
+            Policy::default().permits("nickcao", &node("nrt0", serde_json::json!(null))),
+        ]);
+    }
+}
@@ -1,36 +1,191 @@
-use serde::{de::IgnoredAny, Deserialize, Serialize};
+use crate::asn::{self, Encoding};
+use crate::config::Config;
+use crate::error::Error;
+use crate::topology::Topology;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, net::IpAddr, str::FromStr};
+use time::OffsetDateTime;
 
 pub type Registry = Vec<Organization>;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Organization {
-    pub public_key: String,
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub public_keys: Vec<PublicKey>,
+    /// PEM encoded ca certificate, authenticates nodes by certificate instead of public key
+    #[serde(default)]
+    pub ca_certificate: Option<String>,
     pub organization: String,
     pub nodes: Vec<Node>,
 }
 
+impl Organization {
+    /// Registry entry describing the local node, ready to be published.
+    pub fn stub(config: &Config, public_key: String) -> Self {
+        Self {
+            public_key: Some(public_key),
+            public_keys: vec![],
+            ca_certificate: None,
+            organization: config.organization.clone(),
+            nodes: vec![Node {
+                common_name: config.common_name.clone(),
+                endpoints: config
+                    .endpoints
+                    .iter()
+                    .map(|endpoint| Endpoint {
+                        serial_number: endpoint.serial_number.clone(),
+                        serial_number_encoding: endpoint.serial_number_encoding,
+                        address_family: endpoint.address_family.clone(),
+                        // cidrs are only meaningful locally
+                        address: endpoint
+                            .address
+                            .clone()
+                            .filter(|address| IpAddr::from_str(address).is_ok()),
+                        port: endpoint.port,
+                        options: Options {
+                            encap: endpoint.encap,
+                            mobike: endpoint.mobike,
+                            dpd_delay: None,
+                        },
+                    })
+                    .collect(),
+                options: Options::default(),
+                topology: config.topology.clone(),
+                remarks: None,
+            }],
+        }
+    }
+
+    /// Public keys accepted at the given time, allowing overlap during key rotation.
+    pub fn public_keys(&self, now: OffsetDateTime) -> Vec<String> {
+        self.public_key
+            .iter()
+            .chain(
+                self.public_keys
+                    .iter()
+                    .filter(|key| key.not_before.is_none_or(|not_before| not_before <= now))
+                    .filter(|key| key.not_after.is_none_or(|not_after| now <= not_after))
+                    .map(|key| &key.public_key),
+            )
+            .cloned()
+            .collect()
+    }
+}
+
+/// Drops endpoints whose identity cannot be encoded or was listed before,
+/// returning why each was dropped so that one bad entry does not take down the mesh.
+pub fn validate(registry: &mut Registry) -> Vec<Error> {
+    let mut errors = vec![];
+    let mut seen = HashSet::new();
+    for org in registry {
+        for node in &mut org.nodes {
+            node.endpoints.retain(|endpoint| {
+                let identity = (
+                    org.organization.clone(),
+                    node.common_name.clone(),
+                    endpoint.serial_number.clone(),
+                );
+                let result = asn::validate_identity(
+                    &org.organization,
+                    &node.common_name,
+                    &endpoint.serial_number,
+                    endpoint.serial_number_encoding,
+                )
+                .and_then(|()| {
+                    if seen.insert(identity) {
+                        Ok(())
+                    } else {
+                        Err(Error::InvalidIdentity(format!(
+                            "{}/{}/{} is listed more than once",
+                            org.organization, node.common_name, endpoint.serial_number
+                        )))
+                    }
+                });
+                result.map_err(|err| errors.push(err)).is_ok()
+            });
+        }
+    }
+    errors
+}
+
+/// Looks up an endpoint, along with its node, by its identity attributes.
+pub fn find_endpoint<'a>(
+    registry: &'a Registry,
+    organization: &str,
+    common_name: &str,
+    serial_number: &str,
+) -> Option<(&'a Node, &'a Endpoint)> {
+    registry
+        .iter()
+        .filter(|org| org.organization == organization)
+        .flat_map(|org| &org.nodes)
+        .filter(|node| node.common_name == common_name)
+        .flat_map(|node| node.endpoints.iter().map(move |endpoint| (node, endpoint)))
+        .find(|(_, endpoint)| endpoint.serial_number == serial_number)
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PublicKey {
+    pub public_key: String,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub not_before: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub not_after: Option<OffsetDateTime>,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Node {
     pub common_name: String,
     pub endpoints: Vec<Endpoint>,
-    #[serde(default, skip_serializing)]
-    pub remarks: Option<IgnoredAny>,
+    #[serde(default)]
+    pub options: Options,
+    #[serde(default)]
+    pub topology: Topology,
+    #[serde(default)]
+    pub remarks: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Endpoint {
     pub serial_number: String,
+    #[serde(default)]
+    pub serial_number_encoding: Encoding,
     pub address_family: String,
     pub address: Option<String>,
     pub port: u16,
+    #[serde(default)]
+    pub options: Options,
+}
+
+/// Per-peer tunables, endpoint options take precedence over node options.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    pub encap: Option<bool>,
+    pub mobike: Option<bool>,
+    pub dpd_delay: Option<u64>,
+}
+
+impl Options {
+    pub fn or(&self, fallback: &Options) -> Options {
+        Options {
+            encap: self.encap.or(fallback.encap),
+            mobike: self.mobike.or(fallback.mobike),
+            dpd_delay: self.dpd_delay.or(fallback.dpd_delay),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use time::macros::datetime;
 
     #[test]
     fn deserialize() {
@@ -52,9 +207,15 @@ mod test {
                         "serial_number": "1",
                         "address_family": "ip6",
                         "address": "nrt0.nichi.link",
-                        "port": 4000
+                        "port": 4000,
+                        "options": {
+                          "mobike": true
+                        }
                       }
                     ],
+                    "options": {
+                      "dpd_delay": 30
+                    },
                     "remarks": {
                       "some": "random note",
                       "other": false
@@ -69,4 +230,91 @@ mod test {
 
         insta::assert_yaml_snapshot!(value);
     }
+
+    #[test]
+    fn public_keys() {
+        let data = r#"
+            {
+              "organization": "nickcao",
+              "public_keys": [
+                {
+                  "public_key": "old",
+                  "not_after": "2024-02-01T00:00:00Z"
+                },
+                {
+                  "public_key": "new",
+                  "not_before": "2024-01-01T00:00:00Z"
+                }
+              ],
+              "nodes": []
+            }
+        "#;
+
+        let value: Organization = serde_json::from_str(data).unwrap();
+
+        insta::assert_yaml_snapshot!(value.public_keys(datetime!(2023-12-01 0:00 UTC)));
+        insta::assert_yaml_snapshot!(value.public_keys(datetime!(2024-01-15 0:00 UTC)));
+        insta::assert_yaml_snapshot!(value.public_keys(datetime!(2024-03-01 0:00 UTC)));
+    }
+
+    #[test]
+    fn validate() {
+        let mut registry: Registry = serde_json::from_value(serde_json::json!([
+            {
+                "organization": "acme",
+                "nodes": [
+                    {
+                        "common_name": "server",
+                        "endpoints": [
+                            { "serial_number": "0", "address_family": "ip4", "port": 3000 },
+                            { "serial_number": "0", "address_family": "ip6", "port": 3000 },
+                            { "serial_number": "node_1", "address_family": "ip4", "port": 3000 },
+                            { "serial_number": "node_2", "serial_number_encoding": "utf8", "address_family": "ip4", "port": 3000 }
+                        ]
+                    }
+                ]
+            }
+        ]))
+        .unwrap();
+
+        let errors = super::validate(&mut registry);
+
+        insta::assert_yaml_snapshot!(serde_json::json!({
+            "errors": errors.iter().map(|err| err.to_string()).collect::<Vec<_>>(),
+            "endpoints": registry[0].nodes[0].endpoints,
+        }));
+    }
+
+    #[test]
+    fn stub() {
+        let data = r#"
+            {
+              "organization": "nickcao",
+              "common_name": "nrt0",
+              "endpoints": [
+                {
+                  "serial_number": "0",
+                  "address_family": "ip4",
+                  "address": "10.0.0.0/24",
+                  "port": 3000,
+                  "updown": null,
+                  "fwmark": null
+                },
+                {
+                  "serial_number": "1",
+                  "address_family": "ip6",
+                  "address": "2001:db8::1",
+                  "port": 4000,
+                  "updown": null,
+                  "fwmark": null,
+                  "mobike": true
+                }
+              ]
+            }
+        "#;
+
+        let config: Config = serde_json::from_str(data).unwrap();
+
+        insta::assert_yaml_snapshot!(Organization::stub(&config, "<PEM>".to_string()));
+    }
 }
@@ -0,0 +1,206 @@
+use crate::{
+    address,
+    registry::Registry,
+    topology::{self, Hubs, Member},
+    Report,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Endpoint of a third node, reachable by both ends of an unreachable pair.
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct Relay {
+    pub organization: String,
+    pub common_name: String,
+    pub serial_number: String,
+    pub address: String,
+}
+
+/// Routing hint for a routing daemon, sending traffic to a peer through a relay.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Hint {
+    pub organization: String,
+    pub common_name: String,
+    pub local_serial_number: String,
+    pub remote_serial_number: String,
+    pub relay: Relay,
+}
+
+/// Picks the relay for a pair of nodes, both ends pick the same one.
+///
+/// Candidates are peered with both nodes and have an address in the address family that
+/// resolves with `resolve` to one both can dial, the pair is spread over them by rendezvous
+/// hashing.
+pub fn select(
+    local: &Member,
+    remote: &Member,
+    address_family: &str,
+    registry: &Registry,
+    hubs: &Hubs,
+    resolve: impl Fn(&str, &str) -> Option<String>,
+) -> Option<Relay> {
+    let mut pair = [
+        format!("{}/{}", local.organization, local.common_name),
+        format!("{}/{}", remote.organization, remote.common_name),
+    ];
+    pair.sort();
+
+    topology::nodes(registry)
+        .filter(|(member, _)| {
+            [local, remote].iter().all(|end| {
+                (end.organization, end.common_name) != (member.organization, member.common_name)
+                    && topology::connected(end, member, hubs)
+            })
+        })
+        .flat_map(|(member, node)| {
+            node.endpoints
+                .iter()
+                .filter(|endpoint| endpoint.address_family == address_family)
+                .map(move |endpoint| (member, endpoint))
+        })
+        .filter_map(|(member, endpoint)| {
+            let address = resolve(address_family, endpoint.address.as_deref()?)?;
+            if !address::dialable(None, address.parse().ok()) {
+                return None;
+            }
+            Some(Relay {
+                organization: member.organization.to_string(),
+                common_name: member.common_name.to_string(),
+                serial_number: endpoint.serial_number.clone(),
+                address,
+            })
+        })
+        .min_by_key(|relay| {
+            Sha256::digest(
+                format!(
+                    "{}/{}/{}/{}",
+                    pair[0], pair[1], relay.organization, relay.common_name
+                )
+                .as_bytes(),
+            )
+        })
+}
+
+/// Routing hints for the unreachable connections of a reconciliation.
+pub fn hints(report: &Report) -> Vec<Hint> {
+    let mut hints: Vec<_> = report
+        .relays
+        .iter()
+        .filter_map(|(name, relay)| {
+            let peer = report.peers.get(name)?;
+            Some(Hint {
+                organization: peer.organization.clone(),
+                common_name: peer.common_name.clone(),
+                local_serial_number: peer.local_serial_number.clone(),
+                remote_serial_number: peer.remote_serial_number.clone(),
+                relay: relay.clone(),
+            })
+        })
+        .collect();
+    hints.sort_by(|a, b| {
+        (&a.organization, &a.common_name, &a.local_serial_number).cmp(&(
+            &b.organization,
+            &b.common_name,
+            &b.local_serial_number,
+        ))
+    });
+    hints
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn select() {
+        let registry: Registry = serde_json::from_value(serde_json::json!([
+            {
+                "organization": "acme",
+                "nodes": [
+                    {
+                        "common_name": "nat0",
+                        "endpoints": [{ "serial_number": "0", "address_family": "ip4", "port": 3000 }]
+                    },
+                    {
+                        "common_name": "nat1",
+                        "endpoints": [{ "serial_number": "0", "address_family": "ip4", "port": 3000 }]
+                    },
+                    {
+                        "common_name": "public0",
+                        "endpoints": [
+                            { "serial_number": "0", "address_family": "ip4", "address": "public0.example", "port": 3000 },
+                            { "serial_number": "1", "address_family": "ip6", "address": "2001:db8::1", "port": 3000 }
+                        ]
+                    },
+                    {
+                        "common_name": "public1",
+                        "endpoints": [{ "serial_number": "0", "address_family": "ip4", "address": "192.0.2.2", "port": 3000 }]
+                    },
+                    {
+                        "common_name": "link",
+                        "endpoints": [
+                            { "serial_number": "0", "address_family": "ip4", "address": "169.254.0.1", "port": 3000 },
+                            { "serial_number": "1", "address_family": "ip6", "address": "fe80::1", "port": 3000 }
+                        ]
+                    },
+                    {
+                        "common_name": "loopback",
+                        "endpoints": [
+                            { "serial_number": "0", "address_family": "ip4", "address": "127.0.0.1", "port": 3000 },
+                            { "serial_number": "1", "address_family": "ip6", "address": "::1", "port": 3000 }
+                        ]
+                    },
+                    {
+                        "common_name": "unresolvable",
+                        "endpoints": [{ "serial_number": "0", "address_family": "ip4", "address": "unresolvable.example", "port": 3000 }]
+                    }
+                ]
+            }
+        ]))
+        .unwrap();
+        let hubs = Hubs::new(&registry);
+        let resolve = |_: &str, address: &str| match address {
+            "public0.example" => Some("192.0.2.1".to_string()),
+            "unresolvable.example" => None,
+            address => Some(address.to_string()),
+        };
+
+        let member = |common_name| Member {
+            organization: "acme",
+            common_name,
+            topology: &registry[0]
+                .nodes
+                .iter()
+                .find(|node| node.common_name == common_name)
+                .unwrap()
+                .topology,
+        };
+
+        insta::assert_yaml_snapshot!([
+            super::select(
+                &member("nat0"),
+                &member("nat1"),
+                "ip4",
+                &registry,
+                &hubs,
+                resolve
+            ),
+            super::select(
+                &member("nat1"),
+                &member("nat0"),
+                "ip4",
+                &registry,
+                &hubs,
+                resolve
+            ),
+            super::select(
+                &member("nat0"),
+                &member("nat1"),
+                "ip6",
+                &registry,
+                &hubs,
+                resolve
+            ),
+        ]);
+    }
+}
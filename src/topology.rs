@@ -0,0 +1,244 @@
+use crate::registry::{Node, Registry};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, collections::HashMap};
+
+/// Place of a node in the topology, both ends of an edge must agree on it.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Topology {
+    pub role: Role,
+    /// mesh nodes only peer within their region, spokes prefer hubs of their region
+    pub region: Option<String>,
+    /// number of hubs a spoke peers with
+    pub hubs: usize,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Self {
+            role: Role::Mesh,
+            region: None,
+            hubs: 2,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// peers with hubs and with mesh nodes sharing its region, or all of them without a region
+    #[default]
+    Mesh,
+    /// peers with all hubs, mesh nodes and the spokes that picked it
+    Hub,
+    /// peers only with its nearest hubs
+    Spoke,
+}
+
+/// A node as seen by the topology.
+#[derive(Debug, Clone, Copy)]
+pub struct Member<'a> {
+    pub organization: &'a str,
+    pub common_name: &'a str,
+    pub topology: &'a Topology,
+}
+
+impl Member<'_> {
+    fn same_region(&self, other: &Member) -> bool {
+        match (&self.topology.region, &other.topology.region) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+
+    /// Rendezvous hash, spreading spokes evenly over hubs.
+    fn weight(&self, other: &Member) -> [u8; 32] {
+        Sha256::digest(
+            format!(
+                "{}/{}/{}/{}",
+                self.organization, self.common_name, other.organization, other.common_name
+            )
+            .as_bytes(),
+        )
+        .into()
+    }
+}
+
+/// Whether the topology calls for an edge between two nodes.
+pub fn connected(a: &Member, b: &Member, hubs: &Hubs) -> bool {
+    match (a.topology.role, b.topology.role) {
+        (Role::Hub, Role::Hub) | (Role::Hub, Role::Mesh) | (Role::Mesh, Role::Hub) => true,
+        (Role::Mesh, Role::Mesh) => a.same_region(b),
+        (Role::Spoke, Role::Hub) => hubs.picks(a, b),
+        (Role::Hub, Role::Spoke) => hubs.picks(b, a),
+        (Role::Spoke, _) | (_, Role::Spoke) => false,
+    }
+}
+
+/// Hubs picked by each spoke of a registry, computed once and shared by all edges.
+pub struct Hubs<'a> {
+    hubs: Vec<Member<'a>>,
+    picked: HashMap<(&'a str, &'a str), Vec<Member<'a>>>,
+}
+
+impl<'a> Hubs<'a> {
+    pub fn new(registry: &'a Registry) -> Self {
+        let hubs: Vec<_> = members(registry)
+            .filter(|member| member.topology.role == Role::Hub)
+            .collect();
+        let picked = members(registry)
+            .filter(|member| member.topology.role == Role::Spoke)
+            .map(|spoke| ((spoke.organization, spoke.common_name), pick(&spoke, &hubs)))
+            .collect();
+        Self { hubs, picked }
+    }
+
+    /// Whether the spoke picked the hub, spokes missing from the registry pick on demand.
+    fn picks(&self, spoke: &Member, hub: &Member) -> bool {
+        let picked = match self.picked.get(&(spoke.organization, spoke.common_name)) {
+            Some(picked) => Cow::Borrowed(picked),
+            None => Cow::Owned(pick(spoke, &self.hubs)),
+        };
+        picked.iter().any(|member| {
+            member.organization == hub.organization && member.common_name == hub.common_name
+        })
+    }
+}
+
+/// Hubs of a spoke, preferring those of its own region.
+fn pick<'a>(spoke: &Member, hubs: &[Member<'a>]) -> Vec<Member<'a>> {
+    let mut hubs: Vec<_> = hubs
+        .iter()
+        .map(|member| {
+            (
+                !(spoke.topology.region.is_some() && spoke.same_region(member)),
+                spoke.weight(member),
+                *member,
+            )
+        })
+        .collect();
+    hubs.sort_by_key(|(other_region, weight, _)| (*other_region, *weight));
+    hubs.into_iter()
+        .take(spoke.topology.hubs)
+        .map(|(_, _, member)| member)
+        .collect()
+}
+
+/// Nodes of the registry, along with their place in the topology.
+pub fn nodes(registry: &Registry) -> impl Iterator<Item = (Member<'_>, &Node)> {
+    registry.iter().flat_map(|organization| {
+        organization.nodes.iter().map(|node| {
+            (
+                Member {
+                    organization: &organization.organization,
+                    common_name: &node.common_name,
+                    topology: &node.topology,
+                },
+                node,
+            )
+        })
+    })
+}
+
+fn members(registry: &Registry) -> impl Iterator<Item = Member<'_>> {
+    nodes(registry).map(|(member, _)| member)
+}
+
+/// Edges of a node in the planned topology.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Plan {
+    pub organization: String,
+    pub common_name: String,
+    pub role: Role,
+    /// nodes peered with
+    pub edges: usize,
+    /// connections, one per pair of endpoints sharing an address family
+    pub connections: usize,
+}
+
+/// Plans the topology of the whole registry, regardless of local policies.
+pub fn plan(registry: &Registry) -> Vec<Plan> {
+    let endpoints = |organization: &str, common_name: &str| {
+        registry
+            .iter()
+            .filter(|org| org.organization == organization)
+            .flat_map(|org| &org.nodes)
+            .filter(|node| node.common_name == common_name)
+            .flat_map(|node| &node.endpoints)
+            .collect::<Vec<_>>()
+    };
+
+    let hubs = Hubs::new(registry);
+    members(registry)
+        .map(|local| {
+            let local_endpoints = endpoints(local.organization, local.common_name);
+            let peers: Vec<_> = members(registry)
+                .filter(|remote| {
+                    (remote.organization, remote.common_name)
+                        != (local.organization, local.common_name)
+                })
+                .filter(|remote| connected(&local, remote, &hubs))
+                .collect();
+            Plan {
+                organization: local.organization.to_string(),
+                common_name: local.common_name.to_string(),
+                role: local.topology.role,
+                edges: peers.len(),
+                connections: peers
+                    .iter()
+                    .flat_map(|remote| endpoints(remote.organization, remote.common_name))
+                    .map(|remote| {
+                        local_endpoints
+                            .iter()
+                            .filter(|local| local.address_family == remote.address_family)
+                            .count()
+                    })
+                    .sum(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn plan() {
+        let node = |name: &str, role: &str, region: &str| {
+            serde_json::json!({
+                "common_name": name,
+                "endpoints": [
+                    { "serial_number": "0", "address_family": "ip4", "port": 3000 },
+                    { "serial_number": "1", "address_family": "ip6", "port": 3000 }
+                ],
+                "topology": { "role": role, "region": region, "hubs": 1 }
+            })
+        };
+        let registry: Registry = serde_json::from_value(serde_json::json!([
+            {
+                "organization": "acme",
+                "nodes": [
+                    node("hub-eu", "hub", "eu"),
+                    node("hub-us", "hub", "us"),
+                    node("spoke-eu-0", "spoke", "eu"),
+                    node("spoke-eu-1", "spoke", "eu"),
+                    node("spoke-us-0", "spoke", "us"),
+                    node("spoke-ap-0", "spoke", "ap"),
+                ]
+            },
+            {
+                "organization": "nickcao",
+                "nodes": [
+                    node("mesh-eu-0", "mesh", "eu"),
+                    node("mesh-eu-1", "mesh", "eu"),
+                    node("mesh-us-0", "mesh", "us"),
+                ]
+            }
+        ]))
+        .unwrap();
+
+        insta::assert_yaml_snapshot!(super::plan(&registry));
+    }
+}
@@ -1,51 +1,182 @@
-use crate::error::Error;
+use crate::{config::Timeouts, error::Error, key};
+use async_trait::async_trait;
 use futures::TryStreamExt;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tracing::debug;
 
+#[cfg(test)]
+pub mod mock;
+
 pub struct Client {
     client: rsvici::Client,
+    path: PathBuf,
+    timeouts: Timeouts,
 }
 
 impl Client {
     pub async fn connect<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let client = rsvici::unix::connect(path).await?;
-        Ok(Self { client })
+        let client = rsvici::unix::connect(&path).await?;
+        Ok(Self {
+            client,
+            path: path.as_ref().to_path_buf(),
+            timeouts: Timeouts::default(),
+        })
+    }
+
+    pub fn with_timeouts(self, timeouts: Timeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// Makes a request, failing if charon does not respond within timeout seconds.
+    async fn request<T, U>(
+        &mut self,
+        command: &str,
+        peer: Option<&str>,
+        timeout: u64,
+        message: T,
+    ) -> Result<U, Error>
+    where
+        T: Serialize + Send,
+        U: DeserializeOwned,
+    {
+        let response = self.client.request(command, message);
+        deadline(command, peer, timeout, response).await
+    }
+}
+
+async fn deadline<T, E: Into<Error>>(
+    command: &str,
+    peer: Option<&str>,
+    timeout: u64,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, Error> {
+    match tokio::time::timeout(Duration::from_secs(timeout), future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(Error::Timeout {
+            command: command.to_string(),
+            peer: peer.map(String::from),
+        }),
+    }
+}
+
+/// The vici operations ranet relies on, implemented by [`Client`] or by a stand-in for charon.
+#[async_trait]
+pub trait Transport: Send {
+    async fn version(&mut self) -> Result<Version, Error>;
+    /// Names of the plugins loaded by the daemon.
+    async fn plugins(&mut self) -> Result<Vec<String>, Error>;
+    /// Loads the private key, returning its identifier.
+    async fn load_key(&mut self, key: &[u8]) -> Result<String, Error>;
+    async fn load_cert(&mut self, cert: &str) -> Result<(), Error>;
+    /// Loads the private key on the token, returning its identifier.
+    async fn load_token(&mut self, token: &key::Token) -> Result<String, Error>;
+    /// Identifiers of the private keys loaded by the daemon, by ranet or anyone else.
+    async fn get_keys(&mut self) -> Result<Vec<String>, Error>;
+    async fn unload_key(&mut self, id: &str) -> Result<(), Error>;
+    async fn load_conn(
+        &mut self,
+        name: &str,
+        local: Endpoint,
+        remote: Endpoint,
+        parameters: Parameters,
+    ) -> Result<(), Error>;
+    /// Initiates the connection, unless it already has an sa being established.
+    async fn initiate(&mut self, name: &str) -> Result<(), Error>;
+    /// Terminates the ike sa, waiting for the peer to acknowledge the delete,
+    /// or only sending it when forced.
+    async fn terminate(&mut self, name: &str, force: bool) -> Result<(), Error>;
+    async fn get_conns(&mut self) -> Result<Vec<String>, Error>;
+    async fn unload_conn(&mut self, name: &str) -> Result<(), Error>;
+    async fn list_sas(&mut self, name: Option<&str>) -> Result<SAs, Error>;
+    /// Opens another transport to the same daemon, for requests made concurrently.
+    async fn fork(&mut self) -> Result<Box<dyn Transport>, Error>;
+}
+
+#[async_trait]
+impl Transport for Client {
+    async fn version(&mut self) -> Result<Version, Error> {
+        self.request("version", None, self.timeouts.request, ())
+            .await
     }
-    pub async fn version(&mut self) -> Result<semver::Version, Error> {
-        let v: Version = self.client.request("version", ()).await?;
-        let v = semver::Version::parse(&v.version)?;
-        Ok(v)
+    async fn plugins(&mut self) -> Result<Vec<String>, Error> {
+        let stats: Stats = self
+            .request("stats", None, self.timeouts.request, ())
+            .await?;
+        Ok(stats.plugins)
     }
-    pub async fn load_key(&mut self, key: &[u8]) -> Result<(), Error> {
+    async fn load_key(&mut self, key: &[u8]) -> Result<String, Error> {
         let key = Key {
             r#type: "any",
             data: std::str::from_utf8(key)?,
         };
-        let res: Status = self.client.request("load-key", key).await?;
+        let res: Loaded = self
+            .request("load-key", None, self.timeouts.request, key)
+            .await?;
+        res.parse()
+    }
+    async fn load_cert(&mut self, cert: &str) -> Result<(), Error> {
+        let cert = Cert {
+            r#type: "X509",
+            flag: "NONE",
+            data: cert,
+        };
+        let res: Status = self
+            .request("load-cert", None, self.timeouts.request, cert)
+            .await?;
+        res.parse()
+    }
+    async fn load_token(&mut self, token: &key::Token) -> Result<String, Error> {
+        let token = Token {
+            handle: &token.handle,
+            slot: token.slot,
+            module: token.module.as_deref(),
+            pin: token.pin.as_ref().map(|pin| pin.as_str()),
+        };
+        let res: Loaded = self
+            .request("load-token", None, self.timeouts.request, token)
+            .await?;
+        res.parse()
+    }
+    async fn get_keys(&mut self) -> Result<Vec<String>, Error> {
+        let res: Keys = self
+            .request("get-keys", None, self.timeouts.request, ())
+            .await?;
+        Ok(res.keys)
+    }
+    async fn unload_key(&mut self, id: &str) -> Result<(), Error> {
+        let res: Status = self
+            .request("unload-key", None, self.timeouts.request, UnloadKey { id })
+            .await?;
         res.parse()
     }
-    pub async fn load_conn(
+    async fn load_conn(
         &mut self,
         name: &str,
         local: Endpoint,
         remote: Endpoint,
-        updown: Option<String>,
-        fwmark: Option<String>,
-        iptfs: bool,
+        parameters: Parameters,
     ) -> Result<(), Error> {
-        let conn = Connection::new(local, remote, updown, fwmark, iptfs);
+        let conn = Connection::new(local, remote, parameters);
         let resp: Status = self
-            .client
-            .request("load-conn", HashMap::from([(name, conn)]))
+            .request(
+                "load-conn",
+                Some(name),
+                self.timeouts.request,
+                HashMap::from([(name, conn)]),
+            )
             .await?;
         resp.parse()
     }
-    pub async fn initiate(&mut self, name: &str) -> Result<(), Error> {
-        let sas = self.list_sas(name).await?;
+    async fn initiate(&mut self, name: &str) -> Result<(), Error> {
+        let sas = self.list_sas(Some(name)).await?;
 
-        for sa in sas.iter().flat_map(|v| v.values()) {
+        for sa in sas.values() {
             if !sa.child_sas.is_empty() || sa.tasks_active.contains(&"CHILD_CREATE".to_string()) {
                 return Ok(());
             }
@@ -53,10 +184,12 @@ impl Client {
 
         debug!("initiating sa {}", name);
 
+        // charon replies at once and keeps negotiating in the background
         let _res: Status = self
-            .client
             .request(
                 "initiate",
+                Some(name),
+                self.timeouts.request,
                 Initiate {
                     ike: name,
                     child: "default",
@@ -67,41 +200,85 @@ impl Client {
             .await?;
         Ok(())
     }
-    pub async fn terminate(&mut self, name: &str) -> Result<(), Error> {
-        let _res: Status = self
-            .client
+    async fn terminate(&mut self, name: &str, force: bool) -> Result<(), Error> {
+        // charon gives up first and reports the peer did not acknowledge
+        let res: Status = self
             .request(
                 "terminate",
+                Some(name),
+                self.timeouts.terminate + self.timeouts.request,
                 Terminate {
                     ike: name,
-                    timeout: -1,
+                    force,
+                    timeout: self.timeouts.terminate * 1000,
                 },
             )
             .await?;
-        Ok(())
+        res.parse()
     }
-    pub async fn get_conns(&mut self) -> Result<Vec<String>, Error> {
-        let res: Conns = self.client.request("get-conns", ()).await?;
+    async fn get_conns(&mut self) -> Result<Vec<String>, Error> {
+        let res: Conns = self
+            .request("get-conns", None, self.timeouts.request, ())
+            .await?;
         Ok(res.conns)
     }
-    pub async fn unload_conn(&mut self, name: &str) -> Result<(), Error> {
-        let res: Status = self.client.request("unload-conn", Unload { name }).await?;
+    async fn unload_conn(&mut self, name: &str) -> Result<(), Error> {
+        let res: Status = self
+            .request(
+                "unload-conn",
+                Some(name),
+                self.timeouts.request,
+                Unload { name },
+            )
+            .await?;
         res.parse()
     }
-    async fn list_sas(&mut self, name: &str) -> Result<Vec<HashMap<String, SA>>, Error> {
+    async fn list_sas(&mut self, name: Option<&str>) -> Result<SAs, Error> {
         let sas = self.client.stream_request::<ListSAs, SAs>(
             "list-sas",
             "list-sa",
             ListSAs { ike: name },
         );
+        let sas = deadline(
+            "list-sas",
+            name,
+            self.timeouts.request,
+            sas.try_collect::<Vec<_>>(),
+        )
+        .await?;
+
+        Ok(sas.into_iter().flatten().collect())
+    }
+    async fn fork(&mut self) -> Result<Box<dyn Transport>, Error> {
+        let client = Client::connect(&self.path).await?;
+        Ok(Box::new(client.with_timeouts(self.timeouts)))
+    }
+}
 
-        Ok(sas.try_collect::<Vec<_>>().await?)
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Version {
+    pub daemon: String,
+    pub version: String,
+    pub sysname: String,
+    pub release: String,
+    pub machine: String,
+}
+
+impl Version {
+    /// Release of the daemon, ignoring suffixes such as in 6.0.0dr1.
+    pub fn semver(&self) -> Result<semver::Version, Error> {
+        let end = self
+            .version
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(self.version.len());
+        Ok(semver::Version::parse(&self.version[..end])?)
     }
 }
 
 #[derive(Debug, Deserialize)]
-struct Version {
-    version: String,
+struct Stats {
+    #[serde(default)]
+    plugins: Vec<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -110,6 +287,24 @@ struct Key<'a, 'b> {
     data: &'b str,
 }
 
+#[derive(Debug, Serialize)]
+struct Cert<'a, 'b, 'c> {
+    r#type: &'a str,
+    flag: &'b str,
+    data: &'c str,
+}
+
+#[derive(Debug, Serialize)]
+struct Token<'a> {
+    handle: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slot: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pin: Option<&'a str>,
+}
+
 #[derive(Debug, Deserialize)]
 struct Status {
     success: bool,
@@ -128,6 +323,45 @@ impl Status {
     }
 }
 
+/// Response to loading a private key.
+#[derive(Debug, Deserialize)]
+struct Loaded {
+    success: bool,
+    errmsg: Option<String>,
+    id: Option<String>,
+}
+
+impl Loaded {
+    fn parse(self) -> Result<String, Error> {
+        match self {
+            Loaded {
+                success: true,
+                id: Some(id),
+                ..
+            } => Ok(id),
+            Loaded { success: true, .. } => Err(Error::Protocol(Some(
+                "charon did not return the key identifier".to_string(),
+            ))),
+            Loaded {
+                success: false,
+                errmsg,
+                ..
+            } => Err(Error::Protocol(errmsg)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Keys {
+    #[serde(default)]
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct UnloadKey<'a> {
+    id: &'a str,
+}
+
 #[derive(Debug, Deserialize)]
 struct Conns {
     conns: Vec<String>,
@@ -142,29 +376,55 @@ struct Unload<'a> {
 struct Initiate<'a, 'b> {
     ike: &'a str,
     child: &'b str,
-    timeout: isize,
+    /// milliseconds, negative to return without waiting for the sa
+    timeout: i64,
     init_limits: bool,
 }
 
 #[derive(Debug, Serialize)]
 struct ListSAs<'a> {
-    ike: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ike: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SA {
+    #[serde(default)]
+    pub state: String,
+    #[serde(default)]
+    pub local_id: Option<String>,
+    #[serde(default)]
+    pub remote_id: Option<String>,
+    #[serde(default)]
+    pub tasks_active: Vec<String>,
+    #[serde(default)]
+    pub child_sas: HashMap<String, ChildSA>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
-struct SA {
+pub struct ChildSA {
+    #[serde(default)]
+    pub state: String,
+    #[serde(default)]
+    pub bytes_in: u64,
     #[serde(default)]
-    tasks_active: Vec<String>,
-    child_sas: HashMap<String, serde::de::IgnoredAny>,
+    pub bytes_out: u64,
+    #[serde(default)]
+    pub packets_in: u64,
+    #[serde(default)]
+    pub packets_out: u64,
 }
 
-type SAs = HashMap<String, SA>;
+pub type SAs = HashMap<String, SA>;
 
 #[derive(Debug, Serialize)]
 struct Terminate<'a> {
     ike: &'a str,
-    timeout: isize,
+    force: bool,
+    /// milliseconds
+    timeout: u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -174,16 +434,19 @@ struct Child {
     remote_ts: Vec<String>,
     updown: String,
     mode: &'static str,
-    dpd_action: &'static str,
+    dpd_action: String,
     set_mark_out: String,
-    start_action: &'static str,
-    close_action: &'static str,
+    start_action: String,
+    close_action: String,
 }
 
 #[derive(Debug, Serialize)]
 struct Authentication {
     auth: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pubkeys: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    cacerts: Vec<String>,
     id: String,
 }
 
@@ -200,7 +463,13 @@ struct Connection {
     mobike: bool,
     dpd_delay: u64,
     keyingtries: u32,
-    unique: &'static str,
+    unique: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rekey_time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reauth_time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    over_time: Option<u64>,
     if_id_in: &'static str,
     if_id_out: &'static str,
     local: Authentication,
@@ -208,42 +477,64 @@ struct Connection {
     children: HashMap<&'static str, Child>,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Endpoint {
     pub id: String,
     pub addrs: Vec<String>,
     pub port: u16,
-    pub pubkey: String,
+    /// raw public keys to authenticate with, empty to use a certificate matching the id
+    pub pubkeys: Vec<String>,
+    /// certificate authorities constraining the certificate of the endpoint
+    pub cacerts: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Parameters {
+    pub updown: Option<String>,
+    pub fwmark: Option<String>,
+    pub iptfs: bool,
+    pub version: u32,
+    pub encap: bool,
+    pub mobike: bool,
+    pub dpd_delay: u64,
+    pub keyingtries: u32,
+    pub unique: String,
+    pub rekey_time: Option<u64>,
+    pub reauth_time: Option<u64>,
+    pub over_time: Option<u64>,
+    pub dpd_action: String,
+    pub start_action: String,
+    pub close_action: String,
 }
 
 impl Connection {
-    fn new(
-        local: Endpoint,
-        remote: Endpoint,
-        updown: Option<String>,
-        fwmark: Option<String>,
-        iptfs: bool,
-    ) -> Self {
+    fn new(local: Endpoint, remote: Endpoint, parameters: Parameters) -> Self {
         Self {
-            version: 2,
+            version: parameters.version,
             local_addrs: local.addrs,
             remote_addrs: remote.addrs,
             local_port: local.port,
             remote_port: remote.port,
-            encap: true,
-            mobike: false,
-            dpd_delay: 10,
-            keyingtries: 0,
-            unique: "replace",
+            encap: parameters.encap,
+            mobike: parameters.mobike,
+            dpd_delay: parameters.dpd_delay,
+            keyingtries: parameters.keyingtries,
+            unique: parameters.unique,
+            rekey_time: parameters.rekey_time,
+            reauth_time: parameters.reauth_time,
+            over_time: parameters.over_time,
             if_id_in: "%unique",
             if_id_out: "%unique",
             local: Authentication {
                 auth: "pubkey",
-                pubkeys: vec![local.pubkey],
+                pubkeys: local.pubkeys,
+                cacerts: local.cacerts,
                 id: local.id,
             },
             remote: Authentication {
                 auth: "pubkey",
-                pubkeys: vec![remote.pubkey],
+                pubkeys: remote.pubkeys,
+                cacerts: remote.cacerts,
                 id: remote.id,
             },
             children: HashMap::from([(
@@ -251,12 +542,12 @@ impl Connection {
                 Child {
                     local_ts: vec!["0.0.0.0/0".to_string(), "::/0".to_string()],
                     remote_ts: vec!["0.0.0.0/0".to_string(), "::/0".to_string()],
-                    updown: updown.unwrap_or_default(),
-                    mode: if iptfs { "iptfs" } else { "tunnel" },
-                    dpd_action: "restart",
-                    set_mark_out: fwmark.unwrap_or_default(),
-                    start_action: "none",
-                    close_action: "none",
+                    updown: parameters.updown.unwrap_or_default(),
+                    mode: if parameters.iptfs { "iptfs" } else { "tunnel" },
+                    dpd_action: parameters.dpd_action,
+                    set_mark_out: parameters.fwmark.unwrap_or_default(),
+                    start_action: parameters.start_action,
+                    close_action: parameters.close_action,
                 },
             )]),
         }
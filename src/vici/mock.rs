@@ -0,0 +1,406 @@
+//! In-process stand-in for charon, serving vici on a unix socket and recording requests.
+
+use serde::{de, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+
+const CMD_REQUEST: u8 = 0;
+const CMD_RESPONSE: u8 = 1;
+const CMD_UNKNOWN: u8 = 2;
+const EVENT_REGISTER: u8 = 3;
+const EVENT_UNREGISTER: u8 = 4;
+const EVENT_CONFIRM: u8 = 5;
+const EVENT: u8 = 7;
+
+/// A vici message of any shape, with all values kept as strings.
+#[derive(Debug, Serialize, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum Message {
+    Value(String),
+    List(Vec<Message>),
+    Section(BTreeMap<String, Message>),
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Message;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a vici message")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Message, E> {
+                Ok(Message::Value(v.to_string()))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Message, E> {
+                Ok(Message::Value(String::from_utf8_lossy(v).to_string()))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Message, A::Error> {
+                let mut items = vec![];
+                while let Some(item) = seq.next_element()? {
+                    items.push(item);
+                }
+                Ok(Message::List(items))
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Message, A::Error> {
+                let mut section = BTreeMap::new();
+                while let Some((key, value)) = map.next_entry()? {
+                    section.insert(key, value);
+                }
+                Ok(Message::Section(section))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl Message {
+    fn get(&self, key: &str) -> Option<&Message> {
+        match self {
+            Message::Section(section) => section.get(key),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Message::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct Request {
+    pub command: String,
+    pub message: Message,
+}
+
+/// State of the fake daemon, shared with the test driving it.
+#[derive(Debug)]
+pub struct Charon {
+    pub version: String,
+    pub plugins: Vec<String>,
+    /// identifiers of loaded private keys
+    pub keys: BTreeSet<String>,
+    /// digests of loaded certificates
+    pub certs: BTreeSet<String>,
+    /// names of loaded connections
+    pub conns: BTreeSet<String>,
+    /// ike sas by connection name, as streamed by list-sas
+    pub sas: BTreeMap<String, serde_json::Value>,
+    /// connections whose peer never acknowledges a delete
+    pub unresponsive: BTreeSet<String>,
+    /// commands left without a response
+    pub stalled: BTreeSet<String>,
+    /// error messages of commands made to fail
+    pub errors: BTreeMap<String, String>,
+    /// every command request received, in order
+    pub requests: Vec<Request>,
+}
+
+impl Default for Charon {
+    fn default() -> Self {
+        Self {
+            version: "5.9.14".to_string(),
+            plugins: [
+                "pem",
+                "pkcs8",
+                "pubkey",
+                "x509",
+                "curve25519",
+                "updown",
+                "vici",
+            ]
+            .map(String::from)
+            .to_vec(),
+            keys: BTreeSet::new(),
+            certs: BTreeSet::new(),
+            conns: BTreeSet::new(),
+            sas: BTreeMap::new(),
+            unresponsive: BTreeSet::new(),
+            stalled: BTreeSet::new(),
+            errors: BTreeMap::new(),
+            requests: vec![],
+        }
+    }
+}
+
+pub struct Server {
+    pub charon: Arc<Mutex<Charon>>,
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Server {
+    pub fn start(charon: Charon) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "ranet-mock-{}-{}.vici",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let charon = Arc::new(Mutex::new(charon));
+        let task = tokio::spawn({
+            let charon = charon.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, charon.clone()));
+                }
+            }
+        });
+
+        Self { charon, path, task }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Requests received so far, skipping the list-sas made to check for existing sas.
+    pub fn requests(&self) -> Vec<Request> {
+        self.charon
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|request| request.command != "list-sas")
+            .cloned()
+            .collect()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+async fn send(stream: &mut UnixStream, kind: u8, name: Option<&str>, message: &[u8]) {
+    let mut packet = vec![kind];
+    if let Some(name) = name {
+        packet.push(name.len() as u8);
+        packet.extend_from_slice(name.as_bytes());
+    }
+    packet.extend_from_slice(message);
+    stream.write_u32(packet.len() as u32).await.unwrap();
+    stream.write_all(&packet).await.unwrap();
+}
+
+async fn respond(stream: &mut UnixStream, message: serde_json::Value) {
+    send(
+        stream,
+        CMD_RESPONSE,
+        None,
+        &serde_vici::to_vec(&message).unwrap(),
+    )
+    .await;
+}
+
+async fn serve(mut stream: UnixStream, charon: Arc<Mutex<Charon>>) {
+    while let Ok(len) = stream.read_u32().await {
+        let mut packet = vec![0; len as usize];
+        stream.read_exact(&mut packet).await.unwrap();
+
+        let (kind, rest) = packet.split_first().unwrap();
+        let (name_len, rest) = rest.split_first().unwrap();
+        let (name, payload) = rest.split_at(*name_len as usize);
+        let name = String::from_utf8_lossy(name).to_string();
+
+        match *kind {
+            EVENT_REGISTER | EVENT_UNREGISTER => {
+                send(&mut stream, EVENT_CONFIRM, None, &[]).await;
+                continue;
+            }
+            CMD_REQUEST => {}
+            kind => panic!("unexpected packet type {}", kind),
+        }
+
+        let message: Message = serde_vici::from_slice(payload).unwrap();
+
+        // the lock must not be held across awaits
+        let (wait, (events, response)) = {
+            let mut charon = charon.lock().unwrap();
+            charon.requests.push(Request {
+                command: name.clone(),
+                message: message.clone(),
+            });
+            if charon.stalled.contains(&name) {
+                continue;
+            }
+            (
+                unacknowledged(&charon, &name, &message),
+                handle(&mut charon, &name, &message),
+            )
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+
+        for event in events {
+            send(
+                &mut stream,
+                EVENT,
+                Some("list-sa"),
+                &serde_vici::to_vec(&event).unwrap(),
+            )
+            .await;
+        }
+        match response {
+            Some(response) => respond(&mut stream, response).await,
+            None => send(&mut stream, CMD_UNKNOWN, None, &[]).await,
+        }
+    }
+}
+
+/// How long charon waits for an unresponsive peer to acknowledge a delete, if it does.
+fn unacknowledged(charon: &Charon, command: &str, message: &Message) -> Option<Duration> {
+    let ike = message.get("ike").and_then(Message::as_str)?;
+    let force = message.get("force").and_then(Message::as_str) == Some("yes");
+    let timeout = message.get("timeout").and_then(Message::as_str)?;
+    (command == "terminate"
+        && !force
+        && charon.unresponsive.contains(ike)
+        && charon.sas.contains_key(ike))
+    .then(|| Duration::from_millis(timeout.parse().unwrap()))
+}
+
+fn handle(
+    charon: &mut Charon,
+    command: &str,
+    message: &Message,
+) -> (Vec<serde_json::Value>, Option<serde_json::Value>) {
+    let success = serde_json::json!({ "success": true });
+
+    if let Some(errmsg) = charon.errors.get(command) {
+        return (
+            vec![],
+            Some(serde_json::json!({ "success": false, "errmsg": errmsg })),
+        );
+    }
+
+    match command {
+        "version" => (
+            vec![],
+            Some(serde_json::json!({
+                "daemon": "charon",
+                "version": charon.version,
+                "sysname": "Linux",
+                "release": "6.1.0",
+                "machine": "x86_64",
+            })),
+        ),
+        "stats" => (
+            vec![],
+            Some(serde_json::json!({ "plugins": charon.plugins })),
+        ),
+        "load-key" => {
+            let data = message.get("data").and_then(Message::as_str);
+            let id = digest(data.unwrap_or_default());
+            charon.keys.insert(id.clone());
+            (
+                vec![],
+                Some(serde_json::json!({ "success": true, "id": id })),
+            )
+        }
+        "load-token" => {
+            let handle = message.get("handle").and_then(Message::as_str);
+            let id = handle.unwrap_or_default().to_string();
+            charon.keys.insert(id.clone());
+            (
+                vec![],
+                Some(serde_json::json!({ "success": true, "id": id })),
+            )
+        }
+        "load-cert" => {
+            let data = message.get("data").and_then(Message::as_str);
+            charon.certs.insert(digest(data.unwrap_or_default()));
+            (vec![], Some(success))
+        }
+        "get-keys" => (vec![], Some(serde_json::json!({ "keys": charon.keys }))),
+        "unload-key" => {
+            if let Some(id) = message.get("id").and_then(Message::as_str) {
+                charon.keys.remove(id);
+            }
+            (vec![], Some(success))
+        }
+        "initiate" => (vec![], Some(success)),
+        "terminate" => {
+            let ike = message
+                .get("ike")
+                .and_then(Message::as_str)
+                .unwrap_or_default();
+            let force = message.get("force").and_then(Message::as_str) == Some("yes");
+            if !charon.sas.contains_key(ike) {
+                let errmsg = "no matching SAs to terminate found";
+                return (
+                    vec![],
+                    Some(serde_json::json!({ "success": false, "errmsg": errmsg })),
+                );
+            }
+            if !force && charon.unresponsive.contains(ike) {
+                let errmsg = format!("terminating IKE_SA '{}' timed out", ike);
+                return (
+                    vec![],
+                    Some(serde_json::json!({ "success": false, "errmsg": errmsg })),
+                );
+            }
+            charon.sas.remove(ike);
+            (vec![], Some(success))
+        }
+        "load-conn" => {
+            if let Message::Section(section) = message {
+                charon.conns.extend(section.keys().cloned());
+            }
+            (vec![], Some(success))
+        }
+        "unload-conn" => {
+            if let Some(name) = message.get("name").and_then(Message::as_str) {
+                charon.conns.remove(name);
+            }
+            (vec![], Some(success))
+        }
+        "get-conns" => (vec![], Some(serde_json::json!({ "conns": charon.conns }))),
+        "list-sas" => {
+            let ike = message.get("ike").and_then(Message::as_str);
+            let events = charon
+                .sas
+                .iter()
+                .filter(|(name, _)| ike.is_none_or(|ike| ike == name.as_str()))
+                .map(|(name, sa)| serde_json::json!({ name: sa }))
+                .collect();
+            (events, Some(serde_json::json!({})))
+        }
+        _ => (vec![], None),
+    }
+}
+
+/// Stands in for the fingerprints charon identifies credentials by.
+fn digest(data: &str) -> String {
+    hex::encode(&Sha256::digest(data)[..20])
+}